@@ -11,9 +11,10 @@ fn bench_insert(c: &mut Criterion) {
     eprintln!("Generating random inputs...");
     let all_input = rng
         .sample_iter(&rand::distributions::Standard)
-        .take(all_sizes.clone().rev().next().unwrap())
+        .take(all_sizes.clone().next_back().unwrap())
         .collect::<Vec<u128>>();
     eprintln!("Done.");
+    let bulk_input = all_input.clone();
     c.bench(
         "insert",
         ParameterizedBenchmark::new(
@@ -23,12 +24,19 @@ fn bench_insert(c: &mut Criterion) {
                 bencher.iter(|| {
                     let mut hwt = Hwt::new();
                     for feature in input.clone() {
-                        hwt.insert(feature);
+                        hwt.insert(feature, ());
                     }
                 });
             },
             all_sizes,
         )
+        .with_function(
+            "from_slice",
+            move |bencher: &mut Bencher, &total: &usize| {
+                let input = &bulk_input[0..total];
+                bencher.iter(|| Hwt::from_slice(input));
+            },
+        )
         .throughput(|&n| Throughput::Elements(n as u32)),
     );
 }
@@ -25,7 +25,7 @@ fn bench_neighbors(c: &mut Criterion) {
     eprintln!("Generating random inputs...");
     let all_input = rng
         .sample_iter(&Standard)
-        .take(all_sizes.clone().rev().next().unwrap())
+        .take(all_sizes.clone().next_back().unwrap())
         .collect::<Vec<u128>>();
     let linear_all_input = all_input.clone();
     eprintln!("Done.");
@@ -36,7 +36,7 @@ fn bench_neighbors(c: &mut Criterion) {
         let range = 0..total;
         let mut hwt = Hwt::new();
         for i in range.clone() {
-            hwt.insert(all_input[i]);
+            hwt.insert(all_input[i], i);
         }
         // In the paper they choose 1000 samples that arent in the data set.
         let inliers: Vec<u128> = all_input[0..total]
@@ -44,7 +44,7 @@ fn bench_neighbors(c: &mut Criterion) {
             .map(|&feature| {
                 let mut feature = feature;
                 for bit in 0..128 {
-                    let choice: bool = rng.sample(&bernoulli);
+                    let choice: bool = rng.sample(bernoulli);
                     feature ^= (choice as u128) << bit;
                 }
                 feature
@@ -70,7 +70,7 @@ fn bench_neighbors(c: &mut Criterion) {
                 let mut feature_heap = FeatureHeap::new();
                 bencher.iter(|| {
                     let feature = cycle_range.next().unwrap();
-                    let mut neighbors = [0; 1];
+                    let mut neighbors = [(0, &0); 1];
                     hwt.nearest(
                         feature,
                         128,
@@ -93,7 +93,7 @@ fn bench_neighbors(c: &mut Criterion) {
                 let mut feature_heap = FeatureHeap::new();
                 bencher.iter(|| {
                     let feature = cycle_range.next().unwrap();
-                    let mut neighbors = [0; 1];
+                    let mut neighbors = [(0, &0); 1];
                     hwt.nearest(
                         feature,
                         128,
@@ -115,7 +115,7 @@ fn bench_neighbors(c: &mut Criterion) {
                 let mut feature_heap = FeatureHeap::new();
                 bencher.iter(|| {
                     let feature = cycle_range.next().unwrap();
-                    let mut neighbors = [0; 1];
+                    let mut neighbors = [(0, &0); 1];
                     hwt.nearest(
                         feature,
                         128,
@@ -137,7 +137,7 @@ fn bench_neighbors(c: &mut Criterion) {
                 let mut feature_heap = FeatureHeap::new();
                 bencher.iter(|| {
                     let feature = cycle_range.next().unwrap();
-                    let mut neighbors = [0; 1];
+                    let mut neighbors = [(0, &0); 1];
                     hwt.nearest(
                         feature,
                         128,
@@ -159,7 +159,7 @@ fn bench_neighbors(c: &mut Criterion) {
                 let mut feature_heap = FeatureHeap::new();
                 bencher.iter(|| {
                     let feature = cycle_range.next().unwrap();
-                    let mut neighbors = [0; 1];
+                    let mut neighbors = [(0, &0); 1];
                     hwt.nearest(
                         feature,
                         128,
@@ -181,7 +181,7 @@ fn bench_neighbors(c: &mut Criterion) {
                 let mut feature_heap = FeatureHeap::new();
                 bencher.iter(|| {
                     let feature = cycle_range.next().unwrap();
-                    let mut neighbors = [0; 1];
+                    let mut neighbors = [(0, &0); 1];
                     hwt.nearest(
                         feature,
                         128,
@@ -0,0 +1,184 @@
+//! A compact binary format for storing a built `Hwt`, see `Hwt::write_to`.
+//!
+//! Everything is stored little-endian in this order:
+//!
+//! - The magic bytes `HWT\0`.
+//! - The version of the format as a `u32`.
+//! - The amount of bits in the features as a `u32`.
+//! - The `HwtConfig` as `u64`s: the leaf split threshold, the leaf collapse
+//!   threshold, the 7 precision search thresholds, and the initial leaf
+//!   capacity.
+//! - The amount of entries in the tree as a `u64`.
+//! - The amount of internal nodes as a `u64`, followed by every node.
+//!   A node starts with a `u8` tag.
+//!     - `0` is a leaf vector. It is followed by the amount of leaves as
+//!       a `u64`, then all of the features, then all of the values.
+//!     - `1` is a `HashMap`. It is followed by the amount of leaves under it
+//!       as a `u64`, the amount of children as a `u64`, and then every child
+//!       as its index followed by its node as a `u32`.
+//! - The amount of free nodes as a `u64`, followed by each one as a `u32`.
+//! - The 64-bit FNV-1a checksum of all of the above as a `u64`.
+
+use std::io::{self, Read, Write};
+
+/// The bytes that every stored `Hwt` starts with.
+pub(crate) const MAGIC: [u8; 4] = *b"HWT\0";
+
+/// The version of the format written by `Hwt::write_to`.
+pub(crate) const VERSION: u32 = 1;
+
+/// Lengths read from the input are only trusted up to this amount when
+/// preallocating memory, so that corrupt input can't exhaust the memory
+/// before the checksum is checked.
+const PREALLOCATE_LIMIT: usize = 1 << 16;
+
+/// A value which can be stored in the binary format used by `Hwt::write_to`.
+///
+/// This is implemented for `()`, the primitive integers, and the features.
+pub trait BinaryValue: Sized {
+    /// Writes the value to `writer`.
+    fn write_value<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    /// Reads a value written by `write_value` from `reader`.
+    fn read_value<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+impl BinaryValue for () {
+    fn write_value<W: Write>(&self, _: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_value<R: Read>(_: &mut R) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_binary_int {
+    ($($int:ty),*) => {
+        $(
+            impl BinaryValue for $int {
+                fn write_value<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn read_value<R: Read>(reader: &mut R) -> io::Result<Self> {
+                    let mut bytes = [0; std::mem::size_of::<$int>()];
+                    reader.read_exact(&mut bytes)?;
+                    Ok(<$int>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_binary_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl BinaryValue for usize {
+    fn write_value<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u64).write_value(writer)
+    }
+
+    fn read_value<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let value = u64::read_value(reader)?;
+        if value > usize::MAX as u64 {
+            return Err(invalid_data("hwt: usize too large for this platform"));
+        }
+        Ok(value as usize)
+    }
+}
+
+impl BinaryValue for isize {
+    fn write_value<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as i64).write_value(writer)
+    }
+
+    fn read_value<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let value = i64::read_value(reader)?;
+        if value > isize::MAX as i64 || value < isize::MIN as i64 {
+            return Err(invalid_data("hwt: isize too large for this platform"));
+        }
+        Ok(value as isize)
+    }
+}
+
+macro_rules! impl_binary_array {
+    ($($word:ty, $words:expr);*) => {
+        $(
+            impl BinaryValue for [$word; $words] {
+                fn write_value<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    for word in self {
+                        word.write_value(writer)?;
+                    }
+                    Ok(())
+                }
+
+                fn read_value<R: Read>(reader: &mut R) -> io::Result<Self> {
+                    let mut words = [0; $words];
+                    for word in words.iter_mut() {
+                        *word = <$word>::read_value(reader)?;
+                    }
+                    Ok(words)
+                }
+            }
+        )*
+    };
+}
+
+impl_binary_array!(u64, 2; u64, 4; u64, 8; u128, 2; u128, 4);
+
+/// Creates the error given when the input isn't a valid `Hwt`.
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a length and limits it to a sane amount to preallocate.
+pub(crate) fn read_len<R: Read>(reader: &mut R) -> io::Result<(usize, usize)> {
+    let len = usize::read_value(reader)?;
+    Ok((len, std::cmp::min(len, PREALLOCATE_LIMIT)))
+}
+
+/// A 64-bit FNV-1a hash of all of the bytes passed through it.
+pub(crate) struct Checksummed<I> {
+    pub(crate) inner: I,
+    hash: u64,
+}
+
+impl<I> Checksummed<I> {
+    pub(crate) fn new(inner: I) -> Self {
+        Self {
+            inner,
+            hash: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+
+    pub(crate) fn checksum(&self) -> u64 {
+        self.hash
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash ^= u64::from(byte);
+            self.hash = self.hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.update(&buf[..read]);
+        Ok(read)
+    }
+}
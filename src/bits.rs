@@ -0,0 +1,215 @@
+//! Numbers stored side by side in the bits of a `u128`, which are used to
+//! compute the indices of 128-bit features and to search them.
+//!
+//! `BitsN(n)` is a `u128` `n` where every `N` bits is a separate number.
+//! In the indices of a feature, each of these numbers holds the ones of one
+//! substring of the feature packed into its least significant bits.
+
+use std::ops::Shr;
+
+/// The left half of every substring of `128 >> level` bits.
+const LEFT_MASKS: [u128; 7] = [
+    0xFFFF_FFFF_FFFF_FFFF_0000_0000_0000_0000,
+    0xFFFF_FFFF_0000_0000_FFFF_FFFF_0000_0000,
+    0xFFFF_0000_FFFF_0000_FFFF_0000_FFFF_0000,
+    0xFF00_FF00_FF00_FF00_FF00_FF00_FF00_FF00,
+    0xF0F0_F0F0_F0F0_F0F0_F0F0_F0F0_F0F0_F0F0,
+    0xCCCC_CCCC_CCCC_CCCC_CCCC_CCCC_CCCC_CCCC,
+    0xAAAA_AAAA_AAAA_AAAA_AAAA_AAAA_AAAA_AAAA,
+];
+
+/// The right half of every substring of `128 >> level` bits.
+const RIGHT_MASKS: [u128; 7] = [
+    0x0000_0000_0000_0000_FFFF_FFFF_FFFF_FFFF,
+    0x0000_0000_FFFF_FFFF_0000_0000_FFFF_FFFF,
+    0x0000_FFFF_0000_FFFF_0000_FFFF_0000_FFFF,
+    0x00FF_00FF_00FF_00FF_00FF_00FF_00FF_00FF,
+    0x0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F_0F0F,
+    0x3333_3333_3333_3333_3333_3333_3333_3333,
+    0x5555_5555_5555_5555_5555_5555_5555_5555,
+];
+
+/// This is used when each bit is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits1<N>(pub N);
+
+/// This is used when every `2` bits is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits2<N>(pub N);
+
+/// This is used when every `4` bits is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits4<N>(pub N);
+
+/// This is used when every `8` bits is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits8<N>(pub N);
+
+/// This is used when every `16` bits is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits16<N>(pub N);
+
+/// This is used when every `32` bits is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits32<N>(pub N);
+
+/// This is used when every `64` bits is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits64<N>(pub N);
+
+/// This is used when every `128` bits is a number stored in parallel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Bits128<N>(pub N);
+
+/// Moves the `width`-bit numbers in the right half of `n` apart so that each
+/// one is at the bottom of `2 * width` bits.
+#[inline(always)]
+fn spread(mut n: u128, width: u32) -> u128 {
+    let mut shift = 32;
+    while shift >= width {
+        let level = (6 - shift.trailing_zeros()) as usize;
+        n = (n & LEFT_MASKS[level]) << shift | n & RIGHT_MASKS[level];
+        shift >>= 1;
+    }
+    n
+}
+
+/// The inverse of `spread`, which moves the numbers at the bottom of every
+/// `2 * width` bits together into the right half of the result.
+#[inline(always)]
+fn gather(mut n: u128, width: u32) -> u128 {
+    let mut shift = width;
+    while shift <= 32 {
+        let level = (5 - shift.trailing_zeros()) as usize;
+        n = (n & LEFT_MASKS[level]) >> shift | n & RIGHT_MASKS[level];
+        shift <<= 1;
+    }
+    n
+}
+
+/// Counts the ones in every `2 * width` bits of `n` and puts that many ones
+/// at the bottom of them.
+#[inline(always)]
+fn pack_ones(n: u128, width: u32) -> u128 {
+    // The amount of ones at the bottom of a `u128` made of `count` ones.
+    let ones = |count: u32| (!0u128).checked_shr(128 - count).unwrap_or(0);
+    if width == 1 {
+        // With two bits, `01` and `10` become `01` and `11` stays the same.
+        let low = (n & LEFT_MASKS[6]) >> 1 | n & RIGHT_MASKS[6];
+        let high = n & (n & RIGHT_MASKS[6]) << 1;
+        return low | high;
+    }
+    let segment = 2 * width;
+    let mask = ones(segment);
+    (0..128)
+        .step_by(segment as usize)
+        .fold(0, |packed, offset| {
+            packed | ones((n >> offset & mask).count_ones()) << offset
+        })
+}
+
+macro_rules! impl_bits {
+    ($($bits:ident),*) => {
+        $(
+            impl $bits<u128> {
+                #[inline]
+                pub fn count_ones(self) -> u32 {
+                    self.0.count_ones()
+                }
+            }
+
+            impl Shr<u32> for $bits<u128> {
+                type Output = Self;
+
+                #[inline]
+                fn shr(self, rhs: u32) -> Self {
+                    Self(self.0 >> rhs)
+                }
+            }
+        )*
+    };
+}
+
+impl_bits!(Bits1, Bits2, Bits4, Bits8, Bits16, Bits32, Bits64, Bits128);
+
+macro_rules! impl_bits_halves {
+    ($($bits:ident, $wide:ident, $width:expr);*) => {
+        $(
+            impl $bits<u128> {
+                /// Takes the left and right halves and spreads the numbers
+                /// in each out into twice as many bits.
+                #[inline]
+                pub fn halve(self) -> ($wide<u128>, $wide<u128>) {
+                    (
+                        $wide(spread(self.0 >> 64, $width)),
+                        $wide(spread(self.0 & RIGHT_MASKS[0], $width)),
+                    )
+                }
+
+                /// Puts the numbers in `left` and `right` back into the left
+                /// and right halves. This is the inverse of `halve`.
+                #[inline]
+                pub fn union(left: $wide<u128>, right: $wide<u128>) -> Self {
+                    Self(gather(left.0, $width) << 64 | gather(right.0, $width))
+                }
+
+                /// Moves all of the ones in every pair of numbers to the
+                /// bottom of a number twice as large.
+                #[inline]
+                pub fn pack_ones(self) -> $wide<u128> {
+                    $wide(pack_ones(self.0, $width))
+                }
+            }
+        )*
+    };
+}
+
+impl_bits_halves!(
+    Bits1, Bits2, 1;
+    Bits2, Bits4, 2;
+    Bits4, Bits8, 4;
+    Bits8, Bits16, 8;
+    Bits16, Bits32, 16;
+    Bits32, Bits64, 32;
+    Bits64, Bits128, 64
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_halve_union() {
+        let n = 0xFEED_FACE_CAFE_BEEF_0123_4567_89AB_CDEF;
+        let spread = |half: u128| (0..64).fold(0, |s, i| s | (half >> i & 1) << (2 * i));
+        assert_eq!(
+            Bits1(n).halve(),
+            (Bits2(spread(n >> 64)), Bits2(spread(n & !0u64 as u128)))
+        );
+        assert_eq!(
+            Bits2(0b1101 << 64 | 0b0110).halve(),
+            (Bits4(0b0011_0001), Bits4(0b0001_0010))
+        );
+        assert_eq!(
+            Bits32(0x0BAD_CAFE << 64 | 0xDEAD_BEEF).halve(),
+            (Bits64(0x0BAD_CAFE), Bits64(0xDEAD_BEEF))
+        );
+        let (left, right) = Bits1(n).halve();
+        assert_eq!(Bits1::union(left, right), Bits1(n));
+        let (left, right) = Bits4(n).halve();
+        assert_eq!(Bits4::union(left, right), Bits4(n));
+        let (left, right) = Bits64(n).halve();
+        assert_eq!(Bits64::union(left, right), Bits64(n));
+    }
+
+    #[test]
+    fn test_pack_ones() {
+        assert_eq!(Bits1(0b00_01_10_11).pack_ones(), Bits2(0b00_01_01_11));
+        assert_eq!(Bits2(0b11_01_01_00).pack_ones(), Bits4(0b0111_0001));
+        assert_eq!(Bits2(0b11_00_00_11).pack_ones(), Bits4(0b0011_0011));
+        assert_eq!(Bits4(0b0011_0011).pack_ones(), Bits8(0b0000_1111));
+        assert_eq!(Bits64(0x00FF_00FF).pack_ones(), Bits128(0x0000_FFFF));
+        assert_eq!(Bits64(!0).pack_ones(), Bits128(!0));
+        assert_eq!(Bits32(!0).pack_ones(), Bits64(!0));
+    }
+}
@@ -0,0 +1,170 @@
+//! The thresholds which decide the shape of a `Hwt` and how it is searched.
+
+use crate::binary::invalid_data;
+use crate::BinaryValue;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// The thresholds which decide the shape of a `Hwt` and how it is searched.
+///
+/// The defaults are tuned for uniformly random 128-bit features. Features
+/// which are far from uniform or trees which are much smaller or larger than
+/// a few million entries may be searched faster with other thresholds.
+/// Use `Hwt::with_config` to create a tree with a `HwtConfig`.
+///
+/// ```
+/// # use hwt::{Hwt, HwtConfig};
+/// let config = HwtConfig {
+///     leaf_split_threshold: 1 << 12,
+///     leaf_collapse_threshold: 1 << 10,
+///     ..HwtConfig::default()
+/// };
+/// let mut hwt: Hwt<()> = Hwt::with_config(config).unwrap();
+/// hwt.insert(0b101, ());
+/// assert_eq!(hwt.config().leaf_split_threshold, 1 << 12);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HwtConfig {
+    /// A leaf vector is split into a `HashMap` once it has more leaves than this.
+    ///
+    /// Since searches do a brute force search in a leaf vector, this is also
+    /// the amount of leaves at which a brute force search becomes slower than
+    /// a targeted search.
+    pub leaf_split_threshold: usize,
+    /// If the amount of leaves under a `HashMap` falls below this number
+    /// after a removal, it is collapsed back into a leaf vector.
+    ///
+    /// This should be well below `leaf_split_threshold` so that a node which
+    /// is near the threshold doesn't get repeatedly split and collapsed as
+    /// features are inserted and removed.
+    pub leaf_collapse_threshold: usize,
+    /// The amount of children a `HashMap` at each level of the tree must
+    /// have before it is searched with a precision search instead of
+    /// checking every child.
+    ///
+    /// The reason this is different for each level is that a precision
+    /// search has very different execution times at each level. Higher in
+    /// the tree it is cheap, but lower in the tree it becomes incredibly
+    /// expensive. Thus, this should start low and get higher so that the
+    /// threshold corresponds to the execution complexity of the search.
+    pub precision_search_thresholds: [usize; 7],
+    /// How much space is initially allocated for a leaf vector.
+    pub initial_leaf_capacity: usize,
+}
+
+impl HwtConfig {
+    /// Checks that the thresholds can be used together.
+    ///
+    /// ```
+    /// # use hwt::{ConfigError, HwtConfig};
+    /// assert_eq!(HwtConfig::default().validate(), Ok(()));
+    /// let config = HwtConfig {
+    ///     leaf_split_threshold: 0,
+    ///     ..HwtConfig::default()
+    /// };
+    /// assert_eq!(config.validate(), Err(ConfigError::ZeroLeafSplitThreshold));
+    /// ```
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.leaf_split_threshold == 0 {
+            Err(ConfigError::ZeroLeafSplitThreshold)
+        } else if self.leaf_collapse_threshold > self.leaf_split_threshold {
+            Err(ConfigError::CollapseAboveSplit)
+        } else if self.initial_leaf_capacity > self.leaf_split_threshold {
+            Err(ConfigError::CapacityAboveSplit)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes the config in the format used by `Hwt::write_to`.
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.leaf_split_threshold.write_value(writer)?;
+        self.leaf_collapse_threshold.write_value(writer)?;
+        for threshold in &self.precision_search_thresholds {
+            threshold.write_value(writer)?;
+        }
+        self.initial_leaf_capacity.write_value(writer)
+    }
+
+    /// Reads a config written by `write_to` and checks that it is valid.
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let leaf_split_threshold = usize::read_value(reader)?;
+        let leaf_collapse_threshold = usize::read_value(reader)?;
+        let mut precision_search_thresholds = [0; 7];
+        for threshold in precision_search_thresholds.iter_mut() {
+            *threshold = usize::read_value(reader)?;
+        }
+        let config = Self {
+            leaf_split_threshold,
+            leaf_collapse_threshold,
+            precision_search_thresholds,
+            initial_leaf_capacity: usize::read_value(reader)?,
+        };
+        config
+            .validate()
+            .map_err(|_| invalid_data("hwt: invalid config"))?;
+        Ok(config)
+    }
+}
+
+impl Default for HwtConfig {
+    fn default() -> Self {
+        let leaf_split_threshold = 1 << 17;
+        let start = 128;
+        Self {
+            leaf_split_threshold,
+            leaf_collapse_threshold: leaf_split_threshold >> 2,
+            precision_search_thresholds: [
+                start,
+                start << 1,
+                start << 1,
+                start << 2,
+                start << 2,
+                start << 5,
+                start << 6,
+            ],
+            initial_leaf_capacity: 16,
+        }
+    }
+}
+
+/// The reason a `HwtConfig` is invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// `leaf_split_threshold` is zero.
+    ZeroLeafSplitThreshold,
+    /// `leaf_collapse_threshold` is greater than `leaf_split_threshold`.
+    CollapseAboveSplit,
+    /// `initial_leaf_capacity` is greater than `leaf_split_threshold`.
+    CapacityAboveSplit,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ConfigError::ZeroLeafSplitThreshold => "hwt: leaf_split_threshold must not be zero",
+            ConfigError::CollapseAboveSplit => {
+                "hwt: leaf_collapse_threshold must not exceed leaf_split_threshold"
+            }
+            ConfigError::CapacityAboveSplit => {
+                "hwt: initial_leaf_capacity must not exceed leaf_split_threshold"
+            }
+        })
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Deserializes a `HwtConfig` and rejects it if it is invalid.
+#[cfg(feature = "serde")]
+pub(crate) fn deserialize_valid<'de, D>(deserializer: D) -> Result<HwtConfig, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let config = HwtConfig::deserialize(deserializer)?;
+    config.validate().map_err(serde::de::Error::custom)?;
+    Ok(config)
+}
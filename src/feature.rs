@@ -0,0 +1,666 @@
+//! Binary features of different widths which can be stored in a `Hwt`.
+//!
+//! The tree only needs a few things from a feature: its hamming distance to
+//! other features and the indices of its substring weights at every level of
+//! the tree (see the crate documentation). The index at a level is stored as
+//! the same type as the feature. Each substring of the level holds as many
+//! ones as its weight, packed to the right of the substring. This makes the
+//! hamming distance between two indices the sum of the absolute differences
+//! of their substring weights.
+//!
+//! A feature with `2^B` bits has `B + 1` levels, the last of which is the
+//! feature itself, since every bit is its own substring.
+
+use crate::bits::*;
+use crate::indices::indices128;
+use crate::search::*;
+use crate::{BinaryValue, FeatureHeap};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A binary feature which can be stored in a `Hwt`.
+///
+/// This is implemented for `u64`, `u128`, and arrays of `u64` and `u128`
+/// that make up 128, 256, and 512 bit features.
+pub trait HammingFeature:
+    Copy + Ord + Hash + Debug + Send + Sync + BinaryValue + 'static + private::Sealed
+{
+    /// The number of bits in the feature.
+    const BITS: u32;
+
+    /// The indices of the feature at every level of the tree.
+    type Indices: AsRef<[Self]> + Copy + 'static;
+
+    /// The amount of ones in the feature.
+    fn weight(self) -> u32;
+
+    /// The hamming distance between two features.
+    fn distance(self, other: Self) -> u32;
+
+    /// The hamming distance between two features, only counting the bits
+    /// which are set in `mask`.
+    fn distance_masked(self, other: Self, mask: Self) -> u32;
+
+    /// The sum of the `weights` of the bits which differ between two
+    /// features.
+    ///
+    /// There is a weight for every bit, starting from the least significant
+    /// bit. Arrays are little-endian, so the first word has the first
+    /// weights.
+    fn distance_weighted(self, other: Self, weights: &[u8]) -> u32;
+
+    /// Computes the index of the feature at every level of the tree.
+    fn indices(self) -> Self::Indices;
+
+    /// The number of levels in the tree, which is the number of indices.
+    fn levels() -> usize {
+        Self::BITS.trailing_zeros() as usize + 1
+    }
+
+    /// The largest possible distance between two features whose indices at
+    /// `level` are `a` and `b`.
+    ///
+    /// Each substring of the level contributes the most when the ones of one
+    /// feature line up with the zeros of the other as much as possible.
+    #[doc(hidden)]
+    fn max_distance(level: usize, a: Self, b: Self) -> u32;
+
+    /// The smallest possible distance, only counting the bits set in a mask,
+    /// between two features whose indices at `level` are `a` and `b`.
+    /// `ignored` is the index at `level` of the bits not set in the mask.
+    ///
+    /// The bits that are ignored in a substring can make up for that much of
+    /// the difference between the weights of the substring.
+    #[doc(hidden)]
+    fn min_distance_masked(level: usize, a: Self, b: Self, ignored: Self) -> u32;
+
+    /// The smallest possible weighted distance between two features whose
+    /// indices at `level` are `a` and `b`, where `min_weights` has the
+    /// smallest weight of any bit in each substring of the level.
+    ///
+    /// Every difference between the weights of a substring is at least one
+    /// bit which differs, so it costs at least the smallest weight.
+    #[doc(hidden)]
+    fn min_distance_weighted(level: usize, a: Self, b: Self, min_weights: &[u32]) -> u32;
+
+    /// Flips every bit of the feature.
+    #[doc(hidden)]
+    fn invert(self) -> Self;
+
+    /// Gets all of the possible children indices of a `HashMap` at `level`
+    /// that are exactly `radius` from `sc`. `sp` is the search index at
+    /// `level`, `sc` is the search index at `level + 1`, and `tc` is any of
+    /// the children indices in the `HashMap`.
+    ///
+    /// Returns `None` if there is no way to do this faster than checking
+    /// every child.
+    #[doc(hidden)]
+    fn search_exact(
+        _level: usize,
+        _sp: Self,
+        _sc: Self,
+        _tc: Self,
+        _radius: u32,
+    ) -> Option<Box<dyn Iterator<Item = Self>>> {
+        None
+    }
+
+    /// Gets all of the possible children indices of a `HashMap` at `level`
+    /// that are within `radius` of `sc`. The arguments are the same as
+    /// `search_exact`.
+    ///
+    /// Returns `None` if there is no way to do this faster than checking
+    /// every child.
+    #[doc(hidden)]
+    fn search_radius(
+        _level: usize,
+        _sp: Self,
+        _sc: Self,
+        _tc: Self,
+        _radius: u32,
+    ) -> Option<Box<dyn Iterator<Item = Self>>> {
+        None
+    }
+
+    /// Adds the leaves in the leaf vector `node` to the `FeatureHeap`.
+    #[doc(hidden)]
+    fn add_to_heap(heap: &mut FeatureHeap, search: Self, node: u32, features: &[Self]) {
+        for (ix, &feature) in features.iter().enumerate() {
+            heap.add_one(feature.distance(search), (node, ix as u32));
+        }
+    }
+}
+
+impl private::Sealed for u128 {}
+
+impl HammingFeature for u128 {
+    const BITS: u32 = 128;
+
+    type Indices = [u128; 8];
+
+    #[inline(always)]
+    fn weight(self) -> u32 {
+        self.count_ones()
+    }
+
+    #[inline(always)]
+    fn distance(self, other: Self) -> u32 {
+        (self ^ other).count_ones()
+    }
+
+    #[inline(always)]
+    fn distance_masked(self, other: Self, mask: Self) -> u32 {
+        ((self ^ other) & mask).count_ones()
+    }
+
+    fn distance_weighted(self, other: Self, weights: &[u8]) -> u32 {
+        words_distance_weighted(&[self], &[other], weights)
+    }
+
+    #[inline(always)]
+    fn indices(self) -> [u128; 8] {
+        indices128(self)
+    }
+
+    fn max_distance(level: usize, a: u128, b: u128) -> u32 {
+        words_max_distance(&[a], &[b], 128 >> level)
+    }
+
+    fn min_distance_masked(level: usize, a: u128, b: u128, ignored: u128) -> u32 {
+        words_min_distance_masked(&[a], &[b], &[ignored], 128 >> level)
+    }
+
+    fn min_distance_weighted(level: usize, a: u128, b: u128, min_weights: &[u32]) -> u32 {
+        words_min_distance_weighted(&[a], &[b], min_weights, 128 >> level)
+    }
+
+    fn invert(self) -> Self {
+        !self
+    }
+
+    fn search_exact(
+        level: usize,
+        sp: u128,
+        sc: u128,
+        tc: u128,
+        radius: u32,
+    ) -> Option<Box<dyn Iterator<Item = u128>>> {
+        Some(match level {
+            0 => Box::new(
+                search_exact2(64, Bits128(sp), Bits64(sc), Bits64(tc).pack_ones(), radius)
+                    .map(|Bits64(tc)| tc),
+            ),
+            1 => Box::new(
+                search_exact4(32, Bits64(sp), Bits32(sc), Bits32(tc).pack_ones(), radius)
+                    .map(|Bits32(tc)| tc),
+            ),
+            2 => Box::new(
+                search_exact8(16, Bits32(sp), Bits16(sc), Bits16(tc).pack_ones(), radius)
+                    .map(|Bits16(tc)| tc),
+            ),
+            3 => Box::new(
+                search_exact16(8, Bits16(sp), Bits8(sc), Bits8(tc).pack_ones(), radius)
+                    .map(|Bits8(tc)| tc),
+            ),
+            4 => Box::new(
+                search_exact32(4, Bits8(sp), Bits4(sc), Bits4(tc).pack_ones(), radius)
+                    .map(|Bits4(tc)| tc),
+            ),
+            5 => Box::new(
+                search_exact64(2, Bits4(sp), Bits2(sc), Bits2(tc).pack_ones(), radius)
+                    .map(|Bits2(tc)| tc),
+            ),
+            6 => Box::new(
+                search_exact128(1, Bits2(sp), Bits1(sc), Bits1(tc).pack_ones(), radius)
+                    .map(|Bits1(tc)| tc),
+            ),
+            _ => unreachable!("hwt: there are no children below level 7"),
+        })
+    }
+
+    fn search_radius(
+        level: usize,
+        sp: u128,
+        sc: u128,
+        tc: u128,
+        radius: u32,
+    ) -> Option<Box<dyn Iterator<Item = u128>>> {
+        Some(match level {
+            0 => Box::new(
+                search_radius2(64, Bits128(sp), Bits64(sc), Bits64(tc).pack_ones(), radius)
+                    .map(|(Bits64(tc), _)| tc),
+            ),
+            1 => Box::new(
+                search_radius4(32, Bits64(sp), Bits32(sc), Bits32(tc).pack_ones(), radius)
+                    .map(|(Bits32(tc), _)| tc),
+            ),
+            2 => Box::new(
+                search_radius8(16, Bits32(sp), Bits16(sc), Bits16(tc).pack_ones(), radius)
+                    .map(|(Bits16(tc), _)| tc),
+            ),
+            3 => Box::new(
+                search_radius16(8, Bits16(sp), Bits8(sc), Bits8(tc).pack_ones(), radius)
+                    .map(|(Bits8(tc), _)| tc),
+            ),
+            4 => Box::new(
+                search_radius32(4, Bits8(sp), Bits4(sc), Bits4(tc).pack_ones(), radius)
+                    .map(|(Bits4(tc), _)| tc),
+            ),
+            5 => Box::new(
+                search_radius64(2, Bits4(sp), Bits2(sc), Bits2(tc).pack_ones(), radius)
+                    .map(|(Bits2(tc), _)| tc),
+            ),
+            6 => Box::new(
+                search_radius128(1, Bits2(sp), Bits1(sc), Bits1(tc).pack_ones(), radius)
+                    .map(|(Bits1(tc), _)| tc),
+            ),
+            _ => unreachable!("hwt: there are no children below level 7"),
+        })
+    }
+
+    #[inline(always)]
+    fn add_to_heap(heap: &mut FeatureHeap, search: u128, node: u32, features: &[u128]) {
+        heap.add128(search, node, features);
+    }
+}
+
+/// A word that features are made of.
+trait Word: Copy {
+    const BITS: u32;
+
+    fn count_ones(self) -> u32;
+
+    /// Gets `count` bits of the word starting at bit `offset`.
+    fn bits(self, offset: u32, count: u32) -> Self;
+
+    /// Creates a word with `count` ones starting at bit `offset`.
+    fn ones(offset: u32, count: u32) -> Self;
+
+    fn or(self, other: Self) -> Self;
+
+    fn xor(self, other: Self) -> Self;
+
+    fn is_zero(self) -> bool;
+
+    fn trailing_zeros(self) -> u32;
+
+    /// Clears the lowest bit which is set.
+    fn clear_lowest(self) -> Self;
+}
+
+macro_rules! impl_word {
+    ($word:ty) => {
+        impl Word for $word {
+            const BITS: u32 = std::mem::size_of::<$word>() as u32 * 8;
+
+            #[inline(always)]
+            fn count_ones(self) -> u32 {
+                <$word>::count_ones(self)
+            }
+
+            #[inline(always)]
+            fn bits(self, offset: u32, count: u32) -> Self {
+                (self >> offset) & Self::ones(0, count)
+            }
+
+            #[inline(always)]
+            fn ones(offset: u32, count: u32) -> Self {
+                if count == 0 {
+                    0
+                } else {
+                    (!0 >> (<Self as Word>::BITS - count)) << offset
+                }
+            }
+
+            #[inline(always)]
+            fn or(self, other: Self) -> Self {
+                self | other
+            }
+
+            #[inline(always)]
+            fn xor(self, other: Self) -> Self {
+                self ^ other
+            }
+
+            #[inline(always)]
+            fn is_zero(self) -> bool {
+                self == 0
+            }
+
+            #[inline(always)]
+            fn trailing_zeros(self) -> u32 {
+                <$word>::trailing_zeros(self)
+            }
+
+            #[inline(always)]
+            fn clear_lowest(self) -> Self {
+                self & (self - 1)
+            }
+        }
+    };
+}
+
+impl_word!(u64);
+impl_word!(u128);
+
+/// Computes the index of a feature made of little-endian `words` at the level
+/// where the substrings are `width` bits wide.
+fn words_index<W: Word>(words: &[W], width: u32, index: &mut [W]) {
+    if width <= W::BITS {
+        // Every substring is inside of a single word.
+        for (word, out) in words.iter().zip(index.iter_mut()) {
+            let mut packed = W::ones(0, 0);
+            for offset in (0..W::BITS).step_by(width as usize) {
+                let weight = word.bits(offset, width).count_ones();
+                packed = packed.or(W::ones(offset, weight));
+            }
+            *out = packed;
+        }
+    } else {
+        // Every substring spans several words.
+        let span = (width / W::BITS) as usize;
+        for (substring, out) in words.chunks(span).zip(index.chunks_mut(span)) {
+            let weight: u32 = substring.iter().map(|w| w.count_ones()).sum();
+            for (ix, out) in out.iter_mut().enumerate() {
+                let before = ix as u32 * W::BITS;
+                let count = std::cmp::min(weight.saturating_sub(before), W::BITS);
+                *out = W::ones(0, count);
+            }
+        }
+    }
+}
+
+/// Iterates over the weights of the substrings of an index of a feature made
+/// of little-endian `words` at the level where the substrings are `width`
+/// bits wide.
+fn words_weights<W: Word>(words: &[W], width: u32) -> impl Iterator<Item = u32> + '_ {
+    if width <= W::BITS {
+        // Every substring is inside of a single word.
+        either::Left(words.iter().flat_map(move |word| {
+            (0..W::BITS)
+                .step_by(width as usize)
+                .map(move |offset| word.bits(offset, width).count_ones())
+        }))
+    } else {
+        // Every substring spans several words.
+        let span = (width / W::BITS) as usize;
+        either::Right(
+            words
+                .chunks(span)
+                .map(|substring| substring.iter().map(|w| w.count_ones()).sum()),
+        )
+    }
+}
+
+/// Computes the largest possible distance between two features made of
+/// little-endian words with the indices `a` and `b` at the level where the
+/// substrings are `width` bits wide.
+fn words_max_distance<W: Word>(a: &[W], b: &[W], width: u32) -> u32 {
+    words_weights(a, width)
+        .zip(words_weights(b, width))
+        .map(|(wa, wb)| std::cmp::min(wa + wb, 2 * width - wa - wb))
+        .sum()
+}
+
+/// Computes the smallest possible masked distance between two features made
+/// of little-endian words with the indices `a` and `b` at the level where the
+/// substrings are `width` bits wide, where `ignored` is the index of the bits
+/// which aren't in the mask.
+fn words_min_distance_masked<W: Word>(a: &[W], b: &[W], ignored: &[W], width: u32) -> u32 {
+    words_weights(a, width)
+        .zip(words_weights(b, width))
+        .zip(words_weights(ignored, width))
+        .map(|((wa, wb), wi)| {
+            let difference = std::cmp::max(wa, wb) - std::cmp::min(wa, wb);
+            difference.saturating_sub(wi)
+        })
+        .sum()
+}
+
+/// Computes the smallest possible weighted distance between two features
+/// made of little-endian words with the indices `a` and `b` at the level
+/// where the substrings are `width` bits wide.
+fn words_min_distance_weighted<W: Word>(a: &[W], b: &[W], min_weights: &[u32], width: u32) -> u32 {
+    words_weights(a, width)
+        .zip(words_weights(b, width))
+        .zip(min_weights)
+        .map(|((wa, wb), &weight)| (std::cmp::max(wa, wb) - std::cmp::min(wa, wb)) * weight)
+        .sum()
+}
+
+/// Computes the weighted distance between two features made of little-endian
+/// words.
+fn words_distance_weighted<W: Word>(a: &[W], b: &[W], weights: &[u8]) -> u32 {
+    let mut distance = 0;
+    for (ix, (&a, &b)) in a.iter().zip(b).enumerate() {
+        let mut difference = a.xor(b);
+        while !difference.is_zero() {
+            let bit = ix * W::BITS as usize + difference.trailing_zeros() as usize;
+            distance += u32::from(weights[bit]);
+            difference = difference.clear_lowest();
+        }
+    }
+    distance
+}
+
+impl private::Sealed for u64 {}
+
+impl HammingFeature for u64 {
+    const BITS: u32 = 64;
+
+    type Indices = [u64; 7];
+
+    #[inline(always)]
+    fn weight(self) -> u32 {
+        self.count_ones()
+    }
+
+    #[inline(always)]
+    fn distance(self, other: Self) -> u32 {
+        (self ^ other).count_ones()
+    }
+
+    #[inline(always)]
+    fn distance_masked(self, other: Self, mask: Self) -> u32 {
+        ((self ^ other) & mask).count_ones()
+    }
+
+    fn distance_weighted(self, other: Self, weights: &[u8]) -> u32 {
+        words_distance_weighted(&[self], &[other], weights)
+    }
+
+    fn indices(self) -> [u64; 7] {
+        let mut indices = [0; 7];
+        for (level, index) in indices.iter_mut().enumerate() {
+            words_index(&[self], 64 >> level, std::slice::from_mut(index));
+        }
+        indices
+    }
+
+    fn max_distance(level: usize, a: u64, b: u64) -> u32 {
+        words_max_distance(&[a], &[b], 64 >> level)
+    }
+
+    fn min_distance_masked(level: usize, a: u64, b: u64, ignored: u64) -> u32 {
+        words_min_distance_masked(&[a], &[b], &[ignored], 64 >> level)
+    }
+
+    fn min_distance_weighted(level: usize, a: u64, b: u64, min_weights: &[u32]) -> u32 {
+        words_min_distance_weighted(&[a], &[b], min_weights, 64 >> level)
+    }
+
+    fn invert(self) -> Self {
+        !self
+    }
+}
+
+macro_rules! impl_array_feature {
+    ($word:ty, $words:expr, $levels:expr) => {
+        impl private::Sealed for [$word; $words] {}
+
+        impl HammingFeature for [$word; $words] {
+            const BITS: u32 = <$word as Word>::BITS * $words;
+
+            type Indices = [[$word; $words]; $levels];
+
+            #[inline(always)]
+            fn weight(self) -> u32 {
+                self.iter().map(|word| word.count_ones()).sum()
+            }
+
+            #[inline(always)]
+            fn distance(self, other: Self) -> u32 {
+                self.iter()
+                    .zip(other.iter())
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum()
+            }
+
+            #[inline(always)]
+            fn distance_masked(self, other: Self, mask: Self) -> u32 {
+                self.iter()
+                    .zip(other.iter())
+                    .zip(mask.iter())
+                    .map(|((a, b), m)| ((a ^ b) & m).count_ones())
+                    .sum()
+            }
+
+            fn distance_weighted(self, other: Self, weights: &[u8]) -> u32 {
+                words_distance_weighted(&self, &other, weights)
+            }
+
+            fn indices(self) -> Self::Indices {
+                let mut indices = [[0; $words]; $levels];
+                for (level, index) in indices.iter_mut().enumerate() {
+                    words_index(&self, Self::BITS >> level, index);
+                }
+                indices
+            }
+
+            fn max_distance(level: usize, a: Self, b: Self) -> u32 {
+                words_max_distance(&a, &b, Self::BITS >> level)
+            }
+
+            fn min_distance_masked(level: usize, a: Self, b: Self, ignored: Self) -> u32 {
+                words_min_distance_masked(&a, &b, &ignored, Self::BITS >> level)
+            }
+
+            fn min_distance_weighted(level: usize, a: Self, b: Self, min_weights: &[u32]) -> u32 {
+                words_min_distance_weighted(&a, &b, min_weights, Self::BITS >> level)
+            }
+
+            fn invert(mut self) -> Self {
+                for word in self.iter_mut() {
+                    *word = !*word;
+                }
+                self
+            }
+        }
+    };
+}
+
+impl_array_feature!(u64, 2, 8);
+impl_array_feature!(u64, 4, 9);
+impl_array_feature!(u64, 8, 10);
+impl_array_feature!(u128, 2, 9);
+impl_array_feature!(u128, 4, 10);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_words_index() {
+        // The words must give the same indices as `indices128`.
+        let feature: u128 = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210;
+        let words = [feature as u64, (feature >> 64) as u64];
+        for (index, words_index) in feature.indices().iter().zip(words.indices().iter()) {
+            assert_eq!(*index as u64, words_index[0]);
+            assert_eq!((*index >> 64) as u64, words_index[1]);
+        }
+    }
+
+    #[test]
+    fn test_weight() {
+        let feature: u128 = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210;
+        assert_eq!(feature.weight(), feature.count_ones());
+        assert_eq!((feature as u64).weight(), (feature as u64).count_ones());
+        assert_eq!([feature, !0, 0, 1].weight(), feature.count_ones() + 129);
+        // The index at the root holds as many ones as the feature.
+        assert_eq!(feature.indices()[0].weight(), feature.weight());
+    }
+
+    #[test]
+    fn test_min_distance_masked() {
+        let a: u128 = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210;
+        let b: u128 = 0xFFFF_0000_1111_8888_0000_7777_3333_0001;
+        let mask: u128 = 0xFF00_FF0F_0F0F_3333_FFFF_0000_1234_5678;
+        let (ia, ib, ii) = (a.indices(), b.indices(), mask.invert().indices());
+        let distance = a.distance_masked(b, mask);
+        // The leaf level is the features themselves.
+        assert_eq!(u128::min_distance_masked(7, ia[7], ib[7], ii[7]), distance);
+        for level in 0..8 {
+            assert!(u128::min_distance_masked(level, ia[level], ib[level], ii[level]) <= distance);
+        }
+        // Nothing is ignored with a full mask.
+        for level in 0..8 {
+            assert_eq!(
+                u128::min_distance_masked(level, ia[level], ib[level], 0),
+                ia[level].distance(ib[level])
+            );
+        }
+    }
+
+    #[test]
+    fn test_min_distance_weighted() {
+        let a: [u64; 2] = [0x0123_4567_89AB_CDEF, 0xFEDC_BA98_7654_3210];
+        let b: [u64; 2] = [0xFFFF_0000_1111_8888, 0x0000_7777_3333_0001];
+        let weights = (0..128).map(|bit| (bit % 7) as u8 + 1).collect::<Vec<u8>>();
+        let (ia, ib) = (a.indices(), b.indices());
+        let distance = a.distance_weighted(b, &weights);
+        for level in 0..8 {
+            let min_weights = weights
+                .chunks(128 >> level)
+                .map(|c| u32::from(*c.iter().min().unwrap()))
+                .collect::<Vec<u32>>();
+            let bound =
+                <[u64; 2]>::min_distance_weighted(level, ia[level], ib[level], &min_weights);
+            assert!(bound <= distance);
+            if level == 7 {
+                // The leaf level is the features themselves.
+                assert_eq!(bound, distance);
+            }
+        }
+        // The weights of the second word come after the first word.
+        assert_eq!(
+            [0u64, 1].distance_weighted([0, 0], &weights),
+            u32::from(weights[64])
+        );
+    }
+
+    #[test]
+    fn test_max_distance() {
+        let a: u128 = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210;
+        let b: u128 = 0xFFFF_0000_1111_8888_0000_7777_3333_0001;
+        let (ia, ib) = (a.indices(), b.indices());
+        // The leaf level is the features themselves.
+        assert_eq!(u128::max_distance(7, ia[7], ib[7]), a.distance(b));
+        for level in 0..8 {
+            let max = u128::max_distance(level, ia[level], ib[level]);
+            assert!(max >= a.distance(b));
+            assert!(max >= ia[level].distance(ib[level]));
+        }
+        // The whole feature has one substring.
+        assert_eq!(
+            u128::max_distance(0, ia[0], ib[0]),
+            std::cmp::min(
+                a.count_ones() + b.count_ones(),
+                256 - a.count_ones() - b.count_ones()
+            )
+        );
+    }
+}
@@ -1,13 +1,21 @@
-use packed_simd::{u128x4, u8x4, Cast};
+use crate::popcount::distances128;
+use crate::HammingFeature;
+
+/// The amount of 128-bit features that have their distances computed at once.
+const CHUNK: usize = 64;
+
+/// The location of a leaf in the `Hwt` as the (node, index) pair of the
+/// leaf vector and the position inside of it.
+type Leaf = (u32, u32);
 
 pub struct FeatureHeap {
     cap: usize,
     size: usize,
     in_search: usize,
     search_distance: u32,
-    search: u128,
     worst: u32,
-    features: [Vec<u128>; 129],
+    /// The leaves at every distance from the search feature.
+    features: Vec<Vec<Leaf>>,
 }
 
 impl FeatureHeap {
@@ -16,14 +24,18 @@ impl FeatureHeap {
     }
 
     /// Reset the heap while maintaining the allocated memory.
-    pub(crate) fn reset(&mut self, cap: usize, search: u128) {
+    ///
+    /// `max_distance` is the largest distance a leaf can have, which is the
+    /// number of bits in the features unless the bits are weighted.
+    pub(crate) fn reset(&mut self, cap: usize, max_distance: u32) {
         assert_ne!(cap, 0);
         self.cap = cap;
         self.size = 0;
         self.in_search = 0;
         self.search_distance = 0;
-        self.search = search;
-        self.worst = 128;
+        self.worst = max_distance;
+        self.features
+            .resize_with(max_distance as usize + 1, Vec::new);
         for v in self.features.iter_mut() {
             v.clear();
         }
@@ -39,75 +51,66 @@ impl FeatureHeap {
         self.search_distance = distance;
     }
 
-    /// Add a feature to the search.
+    /// Add the features of the leaf vector `node` to the search for `search`.
+    #[inline(always)]
+    pub(crate) fn add<F: HammingFeature>(&mut self, search: F, node: u32, features: &[F]) {
+        F::add_to_heap(self, search, node, features);
+    }
+
+    /// Add the 128-bit features of the leaf vector `node` to the search for `search`.
     #[inline(always)]
-    pub(crate) fn add(&mut self, features: &[u128]) {
+    pub(crate) fn add128(&mut self, search: u128, node: u32, features: &[u128]) {
+        let distance = |feature: u128| (feature ^ search).count_ones();
         if self.size != self.cap {
             // If we aren't at the cap, every new feature gets inserted,
             // so SIMD would just slow us down.
-            for &feature in features {
-                self.add_one(feature);
+            for (ix, &feature) in features.iter().enumerate() {
+                self.add_one(distance(feature), (node, ix as u32));
             }
         } else {
-            let (before, aligned, after) = unsafe { features.align_to::<u128x4>() };
-            let search = u128x4::splat(self.search);
-            let mut worst = u8x4::splat(self.worst as u8);
-            for &feature in before {
-                self.add_one_cap(feature);
-            }
-            for &feature in aligned {
-                let distance: u8x4 = (feature ^ search).count_ones().cast();
-                // If anything is less than the worst.
-                if (distance - worst).bitmask() != 0 {
-                    let mut local = [0; 4];
-                    feature.write_to_slice_unaligned(&mut local);
-                    // Do the normal horizontal version.
-                    for &feature in &local {
-                        self.add_one_cap(feature);
-                        // Update the worst vector (since it may have changed).
-                        worst = u8x4::splat(self.worst as u8);
-                    }
+            let mut distances = [0; CHUNK];
+            for (chunk, features) in features.chunks(CHUNK).enumerate() {
+                let distances = &mut distances[..features.len()];
+                distances128(search, features, distances);
+                for (ix, &distance) in distances.iter().enumerate() {
+                    let ix = chunk * CHUNK + ix;
+                    self.add_one_cap(u32::from(distance), (node, ix as u32));
                 }
             }
-            for &feature in after {
-                self.add_one_cap(feature);
-            }
         }
     }
 
-    /// Add a feature to the search.
+    /// Add a leaf to the search which is `distance` from the search feature.
     #[inline(always)]
-    pub(crate) fn add_one(&mut self, feature: u128) {
-        let distance = (feature ^ self.search).count_ones();
+    pub(crate) fn add_one(&mut self, distance: u32, leaf: Leaf) {
         // We stop searching once we have enough features under the search distance,
         // so if this is true it will always get added to the FeatureHeap.
         if distance <= self.search_distance {
             self.in_search += 1;
         }
         if self.size != self.cap {
-            self.features[distance as usize].push(feature);
+            self.features[distance as usize].push(leaf);
             self.size += 1;
             // Set the worst feature appropriately.
             if self.size == self.cap {
                 self.update_worst();
             }
         } else if distance < self.worst {
-            self.features[distance as usize].push(feature);
+            self.features[distance as usize].push(leaf);
             self.remove_worst();
         }
     }
 
-    /// Add a feature to the search with the precondition we are already at the cap.
+    /// Add a leaf to the search with the precondition we are already at the cap.
     #[inline(always)]
-    fn add_one_cap(&mut self, feature: u128) {
-        let distance = (feature ^ self.search).count_ones();
+    fn add_one_cap(&mut self, distance: u32, leaf: Leaf) {
         // We stop searching once we have enough features under the search distance,
         // so if this is true it will always get added to the FeatureHeap.
         if distance < self.worst {
             if distance <= self.search_distance {
                 self.in_search += 1;
             }
-            self.features[distance as usize].push(feature);
+            self.features[distance as usize].push(leaf);
             self.remove_worst();
         }
     }
@@ -132,16 +135,31 @@ impl FeatureHeap {
         self.in_search >= self.cap
     }
 
-    pub(crate) fn fill_slice<'a>(&self, s: &'a mut [u128]) -> &'a mut [u128] {
-        let total_fill = std::cmp::min(s.len(), self.size);
-        for (ix, &f) in self
-            .features
+    /// Iterates over the (distance, node, index) of every leaf in the heap
+    /// in order of distance.
+    ///
+    /// The distance is the index of the bucket the leaf is in.
+    pub(crate) fn leaves(&self) -> impl Iterator<Item = (u32, u32, u32)> + '_ {
+        self.features
             .iter()
-            .flat_map(|v| v.iter())
-            .take(total_fill)
             .enumerate()
-        {
-            s[ix] = f;
+            .flat_map(|(distance, v)| {
+                v.iter()
+                    .map(move |&(node, leaf)| (distance as u32, node, leaf))
+            })
+            .take(self.size)
+    }
+
+    /// Fills `s` with the nearest leaves in order of distance, using `lookup`
+    /// to retrieve each leaf from its (distance, node, index).
+    pub(crate) fn fill_slice<'a, T>(
+        &self,
+        s: &'a mut [T],
+        lookup: impl Fn(u32, u32, u32) -> T,
+    ) -> &'a mut [T] {
+        let total_fill = std::cmp::min(s.len(), self.size);
+        for (slot, (distance, node, leaf)) in s.iter_mut().zip(self.leaves()) {
+            *slot = lookup(distance, node, leaf);
         }
         &mut s[0..total_fill]
     }
@@ -154,139 +172,8 @@ impl Default for FeatureHeap {
             size: 0,
             in_search: 0,
             search_distance: 0,
-            search: 0,
             worst: 128,
-            features: [
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-            ],
+            features: vec![vec![]; 129],
         }
     }
 }
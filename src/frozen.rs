@@ -0,0 +1,506 @@
+//! A read-only `Hwt` which is searched directly in a flat buffer of bytes.
+//!
+//! This is created with `Hwt::freeze`. Since the buffer is never parsed into
+//! another data structure, it can be memory-mapped from a file and searched
+//! immediately, no matter how many features are in it.
+//!
+//! Everything is stored little-endian in this order:
+//!
+//! - The magic bytes `HWTF`.
+//! - The version of the format as a `u32`.
+//! - The amount of bits in the features as a `u32`.
+//! - 4 reserved bytes which are always zero.
+//! - The amount of leaves, nodes, and `HashMap` entries as `u64`s.
+//! - The 7 precision search thresholds of the `HwtConfig` as `u64`s.
+//! - Every node as a `u32` kind (`0` for leaves and `1` for a `HashMap`),
+//!   4 reserved bytes, and the `u64` start and `u64` length of its leaves
+//!   or entries. The root is the first node.
+//! - The index of every entry, with the entries of each node sorted by index.
+//! - The child node of every entry as a `u32`.
+//! - The features of every leaf, with the leaves of each node together.
+//!
+//! Leaves are referred to by their position in the buffer, which is also
+//! their position in the values given by `Hwt::freeze`.
+
+use crate::binary::invalid_data;
+use crate::hwt::precise_radius;
+use crate::{BinaryValue, FeatureHeap, HammingFeature, NodeQueue};
+use log::trace;
+use std::io;
+use std::marker::PhantomData;
+
+/// The bytes that every frozen tree starts with.
+const MAGIC: [u8; 4] = *b"HWTF";
+
+/// The version of the frozen layout.
+const VERSION: u32 = 1;
+
+/// The size of the header in bytes.
+const HEADER_SIZE: usize = 96;
+
+/// The size of a node in bytes.
+const NODE_SIZE: usize = 24;
+
+/// A node of the tree as it is written into the buffer by `Hwt::freeze`.
+pub(crate) enum FrozenNode<'a, F> {
+    /// The features of the leaves.
+    Leaves(&'a [F]),
+    /// The (index, node) of every child.
+    Map(Vec<(F, u32)>),
+}
+
+/// A node of the tree as it is read from the buffer.
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    map: bool,
+    start: usize,
+    len: usize,
+}
+
+/// A read-only `Hwt` stored in a flat buffer of bytes.
+///
+/// The buffer `B` can be anything that derefs to bytes, such as a `Vec<u8>`,
+/// a `&[u8]`, or a memory-mapped file. Searches give the position of each
+/// leaf found instead of a value. This position is the index of the value in
+/// the `Vec` given by `Hwt::freeze`.
+///
+/// ```
+/// # use hwt::*;
+/// let mut hwt = Hwt::new();
+/// hwt.insert(0b1001, 'a');
+/// hwt.insert(0b1111, 'b');
+/// let (frozen, values) = hwt.freeze();
+///
+/// // The bytes can be stored anywhere and used again without any parsing.
+/// let frozen: FrozenHwt<&[u8]> = FrozenHwt::new(frozen.as_bytes()).unwrap();
+/// let mut node_queue = NodeQueue::new();
+/// let mut feature_heap = FeatureHeap::new();
+/// let mut neighbors = [(0, 0); 1];
+/// let neighbors = frozen.nearest(
+///     0b1011,
+///     128,
+///     0,
+///     &mut node_queue,
+///     &mut feature_heap,
+///     &mut neighbors,
+/// );
+/// assert_eq!(neighbors[0].0, 0b1001);
+/// assert_eq!(values[neighbors[0].1], &'a');
+/// ```
+pub struct FrozenHwt<B, F = u128> {
+    bytes: B,
+    leaves: usize,
+    nodes: usize,
+    entries: usize,
+    precision_search_thresholds: [usize; 7],
+    _feature: PhantomData<F>,
+}
+
+impl<B, F> FrozenHwt<B, F>
+where
+    B: AsRef<[u8]>,
+    F: HammingFeature,
+{
+    /// Uses a buffer created by `Hwt::freeze` as a tree.
+    ///
+    /// The buffer is validated so that searches can't go out of bounds or
+    /// loop forever, which requires looking at every node but none of the
+    /// leaves.
+    ///
+    /// Returns an error of kind `InvalidData` if the buffer isn't a frozen
+    /// tree of the same feature width.
+    pub fn new(bytes: B) -> io::Result<Self> {
+        let data = bytes.as_ref();
+        if data.len() < HEADER_SIZE || data[0..4] != MAGIC {
+            return Err(invalid_data("hwt: input is not a frozen Hwt"));
+        }
+        if read::<u32>(data, 4) != VERSION {
+            return Err(invalid_data("hwt: unsupported frozen Hwt version"));
+        }
+        if read::<u32>(data, 8) != F::BITS {
+            return Err(invalid_data("hwt: frozen features have a different width"));
+        }
+        let leaves = read::<usize>(data, 16);
+        let nodes = read::<usize>(data, 24);
+        let entries = read::<usize>(data, 32);
+        let mut precision_search_thresholds = [0; 7];
+        for (ix, threshold) in precision_search_thresholds.iter_mut().enumerate() {
+            *threshold = read(data, 40 + ix * 8);
+        }
+        let frozen = Self {
+            bytes,
+            leaves,
+            nodes,
+            entries,
+            precision_search_thresholds,
+            _feature: PhantomData,
+        };
+        if nodes == 0 || frozen.size() != Some(frozen.bytes.as_ref().len()) {
+            return Err(invalid_data("hwt: frozen Hwt has the wrong size"));
+        }
+        for node in 0..nodes as u32 {
+            // The kind of each node must be either a leaf vector or a `HashMap`.
+            if read::<u32>(frozen.as_bytes(), HEADER_SIZE + node as usize * NODE_SIZE) > 1 {
+                return Err(invalid_data("hwt: frozen node has an unknown kind"));
+            }
+            let Node { map, start, len } = frozen.node(node);
+            let end = if map { entries } else { leaves };
+            if start > end || len > end - start {
+                return Err(invalid_data("hwt: frozen node out of bounds"));
+            }
+            if map && (0..len).any(|ix| frozen.child(start + ix) as usize >= nodes) {
+                return Err(invalid_data("hwt: frozen node out of bounds"));
+            }
+        }
+        frozen.check_node(0, 0, &mut vec![false; nodes])?;
+        Ok(frozen)
+    }
+
+    /// Gets the buffer the tree is stored in.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_ref()
+    }
+
+    /// Gets back the buffer the tree is stored in.
+    pub fn into_inner(self) -> B {
+        self.bytes
+    }
+
+    /// Gets the number of leaves in the tree.
+    pub fn len(&self) -> usize {
+        self.leaves
+    }
+
+    /// Checks if the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the feature of the leaf at position `leaf`.
+    pub fn feature(&self, leaf: usize) -> F {
+        read(
+            self.as_bytes(),
+            self.features_offset() + leaf * feature_size::<F>(),
+        )
+    }
+
+    /// Find the nearest neighbors to a feature.
+    ///
+    /// This works exactly like `Hwt::nearest`, except that it gives
+    /// the position of each leaf instead of its value.
+    pub fn nearest<'b>(
+        &self,
+        feature: F,
+        max_weight: u32,
+        max_error: u32,
+        node_queue: &mut NodeQueue,
+        feature_heap: &mut FeatureHeap,
+        dest: &'b mut [(F, usize)],
+    ) -> &'b mut [(F, usize)] {
+        let output = |_, node, ix| {
+            let leaf = self.node(node).start + ix as usize;
+            (self.feature(leaf), leaf)
+        };
+        if dest.is_empty() {
+            return dest;
+        }
+        feature_heap.reset(dest.len(), F::BITS);
+        node_queue.clear(F::BITS);
+        let indices = feature.indices();
+        let indices = indices.as_ref();
+
+        let root = self.node(0);
+        if !root.map {
+            self.add_leaves(feature_heap, feature, 0, root);
+            return feature_heap.fill_slice(dest, output);
+        }
+        for ix in root.start..root.start + root.len {
+            let distance = self.index(ix).distance(indices[0]);
+            if distance <= max_weight {
+                let child = self.child(ix);
+                let node = self.node(child);
+                if node.map {
+                    node_queue.add_one((distance, child, 0));
+                } else {
+                    self.add_leaves(feature_heap, feature, child, node);
+                    if feature_heap.done() {
+                        return feature_heap.fill_slice(dest, output);
+                    }
+                }
+            }
+        }
+
+        for distance in 0..=std::cmp::min(max_weight, F::BITS) {
+            trace!("frozen searching distance({})", distance);
+            feature_heap
+                .search_distance(std::cmp::min(F::BITS, distance.saturating_add(max_error)));
+            if feature_heap.done() {
+                return feature_heap.fill_slice(dest, output);
+            }
+            if node_queue.distance().is_none() {
+                break;
+            }
+            while node_queue.distance() == Some(distance) {
+                if let Some((_, node, level)) = node_queue.pop() {
+                    let level = level as usize;
+                    let internal = self.node(node);
+                    let precise = self
+                        .precision_search_thresholds
+                        .get(level)
+                        .filter(|&&tau| internal.len != 0 && internal.len >= tau)
+                        .and_then(|_| {
+                            F::search_exact(
+                                level,
+                                indices[level],
+                                indices[level + 1],
+                                self.index(internal.start),
+                                distance,
+                            )
+                        });
+                    let search_again = precise.is_some();
+                    let entries = match precise {
+                        None => either::Left(internal.start..internal.start + internal.len),
+                        Some(tcs) => either::Right(tcs.filter_map(|tc| self.find(internal, tc))),
+                    };
+                    for ix in entries {
+                        let child = self.child(ix);
+                        let child_node = self.node(child);
+                        if child_node.map {
+                            let child_distance = self.index(ix).distance(indices[level + 1]);
+                            node_queue.add_one((child_distance, child, level as u8 + 1));
+                        } else {
+                            self.add_leaves(feature_heap, feature, child, child_node);
+                            if feature_heap.done() {
+                                return feature_heap.fill_slice(dest, output);
+                            }
+                        }
+                    }
+                    if search_again && distance != F::BITS {
+                        node_queue.add_one((distance + 1, node, level as u8));
+                    }
+                }
+            }
+        }
+        feature_heap.fill_slice(dest, output)
+    }
+
+    /// Find all neighbors within a given radius.
+    ///
+    /// Yields the features along with the positions of their leaves in no
+    /// particular order.
+    pub fn search_radius<'a>(
+        &'a self,
+        radius: u32,
+        feature: F,
+    ) -> impl Iterator<Item = (F, usize)> + 'a {
+        self.node_scan_radius(radius, feature, feature.indices(), 0, 0)
+    }
+
+    fn node_scan_radius<'a>(
+        &'a self,
+        radius: u32,
+        feature: F,
+        indices: F::Indices,
+        node: u32,
+        level: usize,
+    ) -> Box<dyn Iterator<Item = (F, usize)> + 'a> {
+        let Node { map, start, len } = self.node(node);
+        let recurse =
+            move |ix| self.node_scan_radius(radius, feature, indices, self.child(ix), level + 1);
+        if map {
+            let tc = if len == 0 {
+                None
+            } else {
+                Some(self.index(start))
+            };
+            match precise_radius(
+                &self.precision_search_thresholds,
+                len,
+                tc.as_ref(),
+                radius,
+                indices,
+                level,
+            ) {
+                Some(tcs) => {
+                    let node = self.node(node);
+                    Box::new(
+                        tcs.into_iter()
+                            .filter_map(move |tc| self.find(node, tc))
+                            .flat_map(recurse),
+                    )
+                }
+                None => Box::new(
+                    (start..start + len)
+                        .filter(move |&ix| {
+                            self.index(ix).distance(indices.as_ref()[level]) <= radius
+                        })
+                        .flat_map(recurse),
+                ),
+            }
+        } else {
+            Box::new(
+                (start..start + len)
+                    .map(move |leaf| (self.feature(leaf), leaf))
+                    .filter(move |&(leaf, _)| leaf.distance(feature) <= radius),
+            )
+        }
+    }
+
+    fn add_leaves(&self, feature_heap: &mut FeatureHeap, feature: F, node: u32, leaves: Node) {
+        for ix in 0..leaves.len {
+            let distance = self.feature(leaves.start + ix).distance(feature);
+            feature_heap.add_one(distance, (node, ix as u32));
+        }
+    }
+
+    /// Checks that every node below `node` is reached only once and that
+    /// there are no `HashMap`s on the last level, which means searches
+    /// always end. The nodes must already be in bounds.
+    fn check_node(&self, node: u32, level: usize, seen: &mut [bool]) -> io::Result<()> {
+        let seen_node = &mut seen[node as usize];
+        if *seen_node {
+            return Err(invalid_data("hwt: frozen node is reachable more than once"));
+        }
+        *seen_node = true;
+        let Node { map, start, len } = self.node(node);
+        if map {
+            // The last level can't have children since its index is the feature.
+            if level >= F::levels() - 1 {
+                return Err(invalid_data("hwt: frozen HashMap on the last level"));
+            }
+            for ix in start..start + len {
+                self.check_node(self.child(ix), level + 1, seen)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the entry of `node` with the index `tc`.
+    fn find(&self, node: Node, tc: F) -> Option<usize> {
+        let (mut low, mut high) = (node.start, node.start + node.len);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.index(mid).cmp(&tc) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    fn node(&self, node: u32) -> Node {
+        let offset = HEADER_SIZE + node as usize * NODE_SIZE;
+        let bytes = self.as_bytes();
+        Node {
+            map: read::<u32>(bytes, offset) == 1,
+            start: read(bytes, offset + 8),
+            len: read(bytes, offset + 16),
+        }
+    }
+
+    fn index(&self, entry: usize) -> F {
+        read(
+            self.as_bytes(),
+            self.keys_offset() + entry * feature_size::<F>(),
+        )
+    }
+
+    fn child(&self, entry: usize) -> u32 {
+        read(self.as_bytes(), self.children_offset() + entry * 4)
+    }
+
+    fn keys_offset(&self) -> usize {
+        HEADER_SIZE + self.nodes * NODE_SIZE
+    }
+
+    fn children_offset(&self) -> usize {
+        self.keys_offset() + self.entries * feature_size::<F>()
+    }
+
+    fn features_offset(&self) -> usize {
+        self.children_offset() + self.entries * 4
+    }
+
+    /// Computes the expected size of the buffer, or `None` on overflow.
+    fn size(&self) -> Option<usize> {
+        let node_bytes = self.nodes.checked_mul(NODE_SIZE)?;
+        let entry_bytes = self.entries.checked_mul(feature_size::<F>() + 4)?;
+        let leaf_bytes = self.leaves.checked_mul(feature_size::<F>())?;
+        HEADER_SIZE
+            .checked_add(node_bytes)?
+            .checked_add(entry_bytes)?
+            .checked_add(leaf_bytes)
+    }
+}
+
+/// Lays out the nodes of a tree in a buffer for `FrozenHwt`.
+///
+/// The root must be the first node. The thresholds are those of the `Hwt`.
+pub(crate) fn freeze_nodes<F: HammingFeature>(
+    nodes: &[FrozenNode<'_, F>],
+    precision_search_thresholds: &[usize; 7],
+) -> Vec<u8> {
+    let mut leaves = 0;
+    let mut entries = 0;
+    let mut header = vec![];
+    let mut keys = vec![];
+    let mut children = vec![];
+    let mut features = vec![];
+    for node in nodes {
+        let (kind, start, len) = match node {
+            FrozenNode::Leaves(leaf_features) => {
+                for feature in leaf_features.iter() {
+                    write(&mut features, feature);
+                }
+                leaves += leaf_features.len();
+                (0u32, leaves - leaf_features.len(), leaf_features.len())
+            }
+            FrozenNode::Map(map) => {
+                for (tc, child) in map {
+                    write(&mut keys, tc);
+                    write(&mut children, child);
+                }
+                entries += map.len();
+                (1u32, entries - map.len(), map.len())
+            }
+        };
+        write(&mut header, &kind);
+        write(&mut header, &0u32);
+        write(&mut header, &start);
+        write(&mut header, &len);
+    }
+    let mut bytes = Vec::with_capacity(
+        HEADER_SIZE + header.len() + keys.len() + children.len() + features.len(),
+    );
+    bytes.extend_from_slice(&MAGIC);
+    write(&mut bytes, &VERSION);
+    write(&mut bytes, &F::BITS);
+    write(&mut bytes, &0u32);
+    write(&mut bytes, &leaves);
+    write(&mut bytes, &nodes.len());
+    write(&mut bytes, &entries);
+    for threshold in precision_search_thresholds {
+        write(&mut bytes, threshold);
+    }
+    bytes.extend(header);
+    bytes.extend(keys);
+    bytes.extend(children);
+    bytes.extend(features);
+    bytes
+}
+
+fn feature_size<F: HammingFeature>() -> usize {
+    F::BITS as usize / 8
+}
+
+/// Reads a value at `offset`, which has already been checked to be in bounds.
+fn read<V: BinaryValue>(bytes: &[u8], offset: usize) -> V {
+    V::read_value(&mut &bytes[offset..]).expect("hwt: frozen Hwt read out of bounds")
+}
+
+fn write<V: BinaryValue>(bytes: &mut Vec<u8>, value: &V) {
+    value
+        .write_value(bytes)
+        .expect("hwt: writing to a Vec can't fail");
+}
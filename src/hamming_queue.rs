@@ -1,6 +1,6 @@
-//! This is a special priority queue specifically for 128-bit hamming weight searches.
+//! This is a special priority queue specifically for hamming weight searches.
 //!
-//! This queue works by having 129 vectors, one for each distance. When we find that an internal node
+//! This queue works by having a vector for each distance (129 of them for 128-bit features). When we find that an internal node
 //! achieves a distance of `n` at the least, we place the index of that node into the vector associated
 //! with that distance. Any time we take a node off, we place all of its children into the appropriate
 //! distance priorities.
@@ -15,12 +15,15 @@
 //! this becomes. Assuming randomly distributed features, we expect half of the features to have a distance
 //! below 64, so it is incredibly likely that all removals are constant time since we will always encounter
 //! a removal below or equal to 64.
+//!
+//! Nodes are referred to by their index in the `Hwt`, so the queue doesn't borrow the tree. This allows
+//! the queue to be reused across searches and trees without any unsafe code.
 
 use std::fmt;
-use crate::InternalMap;
 
-type Distances = [Vec<(&'static InternalMap, u8)>; 129];
-type NodeEntry = (u32, &'static InternalMap, u8);
+type Distances = Vec<Vec<(u32, u8)>>;
+/// The (distance, node, level) of an internal node.
+type NodeEntry = (u32, u32, u8);
 
 #[derive(Clone)]
 pub struct NodeQueue {
@@ -37,7 +40,12 @@ impl NodeQueue {
     }
 
     /// This allows the queue to be cleared so that we don't need to reallocate memory.
-    pub(crate) fn clear(&mut self) {
+    ///
+    /// `max_distance` is the largest distance a node can have, which is the
+    /// number of bits in the features unless the bits are weighted.
+    pub(crate) fn clear(&mut self, max_distance: u32) {
+        self.distances
+            .resize_with(max_distance as usize + 1, Vec::new);
         for v in self.distances.iter_mut() {
             v.clear();
         }
@@ -49,7 +57,7 @@ impl NodeQueue {
         loop {
             if let Some((node, level)) = self.distances[self.lowest].pop() {
                 return Some((self.lowest as u32, node, level));
-            } else if self.lowest == 128 {
+            } else if self.lowest == self.distances.len() - 1 {
                 return None;
             } else {
                 self.lowest += 1;
@@ -57,7 +65,7 @@ impl NodeQueue {
         }
     }
 
-    /// Takes an iterator over (distance, tp, node, level)
+    /// Adds an internal node at the given (distance, node, level)
     #[inline]
     pub(crate) fn add_one(&mut self, (distance, node, level): NodeEntry) {
         self.distances[distance as usize].push((node, level));
@@ -81,137 +89,7 @@ impl fmt::Debug for NodeQueue {
 impl Default for NodeQueue {
     fn default() -> Self {
         Self {
-            distances: [
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-                vec![],
-            ],
+            distances: vec![vec![]; 129],
             lowest: 0,
         }
     }
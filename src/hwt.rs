@@ -1,78 +1,220 @@
-use crate::indices::*;
-use crate::search::*;
-use crate::{FeatureHeap, NodeQueue};
+use crate::binary::{invalid_data, read_len, Checksummed, MAGIC, VERSION};
+use crate::frozen::{freeze_nodes, FrozenNode};
+use crate::stats;
+use crate::{
+    BinaryValue, ConfigError, FeatureHeap, FrozenHwt, HammingFeature, HwtConfig, Knn, NearestIter,
+    Neighbor, NodeQueue, QueryStats, TreeStats,
+};
 use hashbrown::HashMap;
 use log::trace;
-use swar::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
 
-/// This threshold determines whether to perform a brute-force search in a bucket
-/// instead of a targeted search if the amount of nodes is less than this number.
+mod join;
+
+/// A predicate which decides if a leaf may be found by a search.
+pub(crate) type Filter<'f, T, F> = Option<&'f mut dyn FnMut(F, &T) -> bool>;
+
+pub(crate) type InternalMap<F> = HashMap<F, u32, std::hash::BuildHasherDefault<ahash::AHasher>>;
+
+/// Estimates the heap memory in bytes used by `map` from its capacity.
 ///
-/// Since we do a brute force search in an internal node with < `TAU` leaves,
-/// this also defines the threshold at which a vector must be split into a hash table.
-const TAU: usize = 1 << 17;
-
-/// The threshold at which we change to precision search for each level of the tree.
-/// The reason this is different for each level is that `search_exact2` and
-/// `search_exact128` have very different execution times. Higher in the tree,
-/// it is cheaper to do an exact or radius search, but lower in the tree it becomes
-/// incredibly expensive. Thus, this should start low and get higher so that the
-/// threshold corresponds to execution complexity of the search.
-const TABLE_TAUS: [usize; 7] = [
-    START_TAU_MAG,
-    START_TAU_MAG << 1,
-    START_TAU_MAG << 1,
-    START_TAU_MAG << 2,
-    START_TAU_MAG << 2,
-    START_TAU_MAG << 5,
-    START_TAU_MAG << 6,
-];
-const START_TAU_MAG: usize = 128;
-
-/// This determines how much space is initially allocated for a leaf vector.
-const INITIAL_CAPACITY: usize = 16;
-
-pub(crate) type InternalMap = HashMap<u128, u32, std::hash::BuildHasherDefault<ahash::AHasher>>;
+/// The table has a power of two buckets that are at most 7/8 full, and a
+/// control byte for every bucket plus a group of extra control bytes.
+fn map_heap_bytes<F>(map: &InternalMap<F>) -> usize {
+    let capacity = map.capacity();
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = if capacity < 8 {
+        (capacity + 1).next_power_of_two()
+    } else {
+        (capacity * 8 / 7).next_power_of_two()
+    };
+    buckets * (std::mem::size_of::<(F, u32)>() + 1) + 16
+}
 
 #[derive(Debug)]
-enum Internal {
-    /// This always contains features.
-    Vec(Vec<u128>),
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "T: Serialize, F: HammingFeature + Serialize",
+        deserialize = "T: Deserialize<'de>, F: HammingFeature + Deserialize<'de>"
+    ))
+)]
+enum Internal<T, F> {
+    /// This always contains features and the values associated with them.
+    ///
+    /// The features are kept in their own `Vec` so that they can be scanned
+    /// contiguously. The value at any index belongs to the feature at the
+    /// same index.
+    Vec(Vec<F>, Vec<T>),
     /// This always points to another internal node.
-    Map(InternalMap),
+    #[cfg_attr(feature = "serde", serde(with = "serde_map"))]
+    Map(InternalMap<F>),
 }
 
-impl Default for Internal {
-    fn default() -> Self {
-        Internal::Vec(Vec::with_capacity(INITIAL_CAPACITY))
+/// Serializes an `InternalMap` as a sequence of (index, node) pairs.
+#[cfg(feature = "serde")]
+mod serde_map {
+    use super::InternalMap;
+    use crate::HammingFeature;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<F, S>(map: &InternalMap<F>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        F: HammingFeature + Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, F, D>(deserializer: D) -> Result<InternalMap<F>, D::Error>
+    where
+        F: HammingFeature + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(F, u32)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+impl<T, F> Internal<T, F> {
+    /// Makes an empty leaf vector with room for `capacity` leaves.
+    fn with_capacity(capacity: usize) -> Self {
+        Internal::Vec(Vec::with_capacity(capacity), Vec::with_capacity(capacity))
     }
 }
 
-pub struct Hwt {
+/// A Hamming Weight Tree which maps features of type `F` to values of type `T`.
+///
+/// Features are 128 bits by default. Any `HammingFeature` can be used instead
+/// by creating the tree with `Hwt::default()`:
+///
+/// ```
+/// # use hwt::Hwt;
+/// let mut hwt: Hwt<(), [u64; 4]> = Hwt::default();
+/// hwt.insert([0b1001, 0, 0, 0b1], ());
+/// hwt.insert([0b1000, 0, 0, 0b1], ());
+/// let neighbors = hwt.knn([0b1000, 0, 0, 0]).run();
+/// assert_eq!(neighbors[0].feature, [0b1000, 0, 0, 0b1]);
+/// assert_eq!(neighbors[0].distance, 1);
+/// ```
+///
+/// Several entries may share the same feature, in which case each of them
+/// is retained along with its own value.
+///
+/// A built `Hwt` can be stored with `write_to` and loaded with `read_from`.
+/// With the `serde` feature it can also be used with any serde format.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(
+            serialize = "T: Serialize, F: HammingFeature + Serialize",
+            deserialize = "T: Deserialize<'de>, F: HammingFeature + Deserialize<'de>"
+        ),
+        try_from = "UncheckedHwt<T, F>"
+    )
+)]
+pub struct Hwt<T, F = u128> {
     /// A `u32` pointing to an internal node is just an index into the
     /// internals array, which is just a bump allocator for internal nodes.
-    internals: Vec<Internal>,
+    internals: Vec<Internal<T, F>>,
+    /// The amount of leaves under each internal node. This is only kept
+    /// up to date for `Internal::Map` nodes.
+    counts: Vec<usize>,
+    /// Internal nodes which were freed when a node was collapsed and can be
+    /// reused by the bump allocator.
+    free: Vec<u32>,
+    count: usize,
+    /// The thresholds used by this tree.
+    pub(crate) config: HwtConfig,
+}
+
+/// The fields of a deserialized `Hwt` before `Hwt::check` has been run on them.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de>, F: HammingFeature + Deserialize<'de>"))]
+struct UncheckedHwt<T, F> {
+    internals: Vec<Internal<T, F>>,
+    counts: Vec<usize>,
+    free: Vec<u32>,
     count: usize,
+    #[serde(deserialize_with = "crate::config::deserialize_valid")]
+    config: HwtConfig,
 }
 
-impl Hwt {
-    /// Makes an empty `Hwt`.
+#[cfg(feature = "serde")]
+impl<T, F: HammingFeature> std::convert::TryFrom<UncheckedHwt<T, F>> for Hwt<T, F> {
+    type Error = &'static str;
+
+    fn try_from(unchecked: UncheckedHwt<T, F>) -> Result<Self, Self::Error> {
+        let hwt = Self {
+            internals: unchecked.internals,
+            counts: unchecked.counts,
+            free: unchecked.free,
+            count: unchecked.count,
+            config: unchecked.config,
+        };
+        hwt.check()?;
+        Ok(hwt)
+    }
+}
+
+impl<T> Hwt<T> {
+    /// Makes an empty `Hwt` of 128-bit features.
     ///
     /// ```
     /// # use hwt::Hwt;
-    /// let hwt = Hwt::new();
+    /// let hwt: Hwt<()> = Hwt::new();
     /// assert!(hwt.is_empty());
     /// ```
     pub fn new() -> Self {
         Self::default()
     }
+}
+
+impl<T, F: HammingFeature> Hwt<T, F> {
+    /// Makes an empty `Hwt` which uses the thresholds in `config`.
+    ///
+    /// Returns an error if the config is invalid, see `HwtConfig::validate`.
+    ///
+    /// ```
+    /// # use hwt::{Hwt, HwtConfig};
+    /// let config = HwtConfig {
+    ///     initial_leaf_capacity: 64,
+    ///     ..HwtConfig::default()
+    /// };
+    /// let hwt: Hwt<(), [u64; 2]> = Hwt::with_config(config).unwrap();
+    /// assert_eq!(hwt.config(), &config);
+    /// ```
+    pub fn with_config(config: HwtConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            internals: vec![Internal::with_capacity(config.initial_leaf_capacity)],
+            counts: vec![0],
+            free: vec![],
+            count: 0,
+            config,
+        })
+    }
+
+    /// Gets the thresholds used by the `Hwt`.
+    pub fn config(&self) -> &HwtConfig {
+        &self.config
+    }
 
     /// Gets the number of entries in the `Hwt`.
     ///
     /// ```
     /// # use hwt::Hwt;
     /// let mut hwt = Hwt::new();
-    /// hwt.insert(0b101);
+    /// hwt.insert(0b101, ());
     /// assert_eq!(hwt.len(), 1);
     /// ```
     pub fn len(&self) -> usize {
@@ -85,38 +227,146 @@ impl Hwt {
     /// # use hwt::Hwt;
     /// let mut hwt = Hwt::new();
     /// assert!(hwt.is_empty());
-    /// hwt.insert(0b101);
+    /// hwt.insert(0b101, ());
     /// assert!(!hwt.is_empty());
     /// ```
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
+    /// Iterates over every feature in the `Hwt` along with its value in no
+    /// particular order.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b101, 'a');
+    /// hwt.insert(0b100, 'b');
+    /// let mut entries = hwt.iter().collect::<Vec<_>>();
+    /// entries.sort_unstable();
+    /// assert_eq!(entries, vec![(0b100, &'b'), (0b101, &'a')]);
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = (F, &T)> {
+        // Free nodes are always empty leaf vectors.
+        self.internals.iter().flat_map(|internal| match internal {
+            Internal::Vec(features, values) => either::Left(features.iter().cloned().zip(values)),
+            Internal::Map(_) => either::Right(std::iter::empty()),
+        })
+    }
+
+    /// Gets the shape and memory usage of the `Hwt`.
+    ///
+    /// This visits every node, but none of the leaves unless the root is
+    /// a leaf vector.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let hwt = Hwt::from_slice(&[0b1001u128, 0b1111]);
+    /// let stats = hwt.stats();
+    /// assert_eq!(stats.len, 2);
+    /// assert_eq!(stats.depth, 1);
+    /// assert!(stats.heap_bytes() >= 2 * 16 + 2 * 8);
+    /// ```
+    pub fn stats(&self) -> TreeStats {
+        use std::mem::size_of;
+        let mut stats = TreeStats {
+            len: self.count,
+            free_nodes: self.free.len(),
+            internals_bytes: self.internals.capacity() * size_of::<Internal<T, F>>()
+                + self.counts.capacity() * size_of::<usize>()
+                + self.free.capacity() * size_of::<u32>(),
+            root_weights: vec![0.0; F::BITS as usize + 1],
+            ..TreeStats::default()
+        };
+        let mut nodes = vec![(0, 0)];
+        while let Some((node, level)) = nodes.pop() {
+            match &self.internals[node as usize] {
+                Internal::Vec(features, values) => {
+                    stats::increment(&mut stats.leaf_nodes, level);
+                    stats::increment(&mut stats.leaf_sizes, stats::bits(features.len()));
+                    stats.leaf_bytes +=
+                        features.capacity() * size_of::<F>() + values.capacity() * size_of::<T>();
+                }
+                Internal::Map(map) => {
+                    stats::increment(&mut stats.map_nodes, level);
+                    stats::increment(&mut stats.map_fan_out, stats::bits(map.len()));
+                    stats.map_bytes += map_heap_bytes(map);
+                    nodes.extend(map.values().map(|&child| (child, level + 1)));
+                }
+            }
+        }
+        stats.depth = std::cmp::max(stats.leaf_nodes.len(), stats.map_nodes.len());
+        stats.leaf_nodes.resize(stats.depth, 0);
+        stats.map_nodes.resize(stats.depth, 0);
+
+        let weight = |feature: F| feature.weight() as usize;
+        match &self.internals[0] {
+            Internal::Vec(features, _) => {
+                for &feature in features {
+                    stats.root_weights[weight(feature)] += 1.0;
+                }
+            }
+            // The index at the root holds as many ones as the weight.
+            Internal::Map(map) => {
+                for (&tc, &child) in map {
+                    stats.root_weights[weight(tc)] += match &self.internals[child as usize] {
+                        Internal::Vec(features, _) => features.len(),
+                        Internal::Map(_) => self.counts[child as usize],
+                    } as f64;
+                }
+            }
+        }
+        if self.count != 0 {
+            for fraction in &mut stats.root_weights {
+                *fraction /= self.count as f64;
+            }
+        }
+        stats
+    }
+
     fn allocate_internal(&mut self) -> u32 {
+        if let Some(internal) = self.free.pop() {
+            self.internals[internal as usize] =
+                Internal::with_capacity(self.config.initial_leaf_capacity);
+            self.counts[internal as usize] = 0;
+            return internal;
+        }
         let internal = self.internals.len() as u32;
-        assert!(internal < std::u32::MAX);
-        self.internals.push(Internal::default());
+        assert!(internal < u32::MAX);
+        self.internals
+            .push(Internal::with_capacity(self.config.initial_leaf_capacity));
+        self.counts.push(0);
         internal
     }
 
+    /// Releases the memory of an internal node and places it on the free list.
+    fn free_internal(&mut self, internal: u32) {
+        self.internals[internal as usize] = Internal::Vec(Vec::new(), Vec::new());
+        self.free.push(internal);
+    }
+
     /// Converts an internal node from a `Vec` of leaves to a `HashMap` from indices to internal nodes.
     ///
     /// `internal` must be the internal node index which should be replaced
-    /// `level` must be set from 0 to 7 inclusive. If it is 0, this is the root.
+    /// `level` must be below the amount of levels of the feature. If it is 0, this is the root.
     fn convert(&mut self, internal: usize, level: usize) {
         // Swap a temporary vec with the one in the store to avoid the wrath of the borrow checker.
-        let mut old_vec = Internal::Vec(Vec::new());
+        let mut old_vec = Internal::Vec(Vec::new(), Vec::new());
         std::mem::swap(&mut self.internals[internal], &mut old_vec);
         // Use the old vec to create a new map for the node.
         self.internals[internal] = match old_vec {
-            Internal::Vec(v) => {
+            Internal::Vec(features, values) => {
+                self.counts[internal] = features.len();
                 let mut map = InternalMap::default();
-                for feature in v.into_iter() {
-                    let index = indices128(feature)[level];
+                for (feature, value) in features.into_iter().zip(values) {
+                    let index = feature.indices().as_ref()[level];
                     let new_internal =
                         *map.entry(index).or_insert_with(|| self.allocate_internal());
-                    if let Internal::Vec(ref mut v) = self.internals[new_internal as usize] {
-                        v.push(feature);
+                    if let Internal::Vec(ref mut features, ref mut values) =
+                        self.internals[new_internal as usize]
+                    {
+                        features.push(feature);
+                        values.push(value);
                     } else {
                         unreachable!(
                             "cannot have InternalStore::Map in subtable when just created"
@@ -129,39 +379,120 @@ impl Hwt {
         }
     }
 
-    /// Inserts an item ID to the `Hwt`.
+    /// Converts an internal node from a `HashMap` back into a `Vec` containing
+    /// every leaf in its subtree. This is the inverse of `convert`.
     ///
-    /// - `F`: A function which should give the `feature` for the given ID.
+    /// All of the internal nodes below `internal` are freed.
+    fn collapse(&mut self, internal: usize) {
+        let mut features = Vec::with_capacity(self.counts[internal]);
+        let mut values = Vec::with_capacity(self.counts[internal]);
+        let old_map = std::mem::replace(
+            &mut self.internals[internal],
+            Internal::Vec(Vec::new(), Vec::new()),
+        );
+        match old_map {
+            Internal::Map(map) => {
+                for (_, child) in map {
+                    self.take_subtree(child, &mut features, &mut values);
+                }
+            }
+            _ => panic!("tried to collapse an InternalStore::Vec"),
+        }
+        self.internals[internal] = Internal::Vec(features, values);
+    }
+
+    /// Moves every leaf under `internal` into `features` and `values` and
+    /// frees all of the internal nodes in the subtree.
+    fn take_subtree(&mut self, internal: u32, features: &mut Vec<F>, values: &mut Vec<T>) {
+        let old = std::mem::replace(
+            &mut self.internals[internal as usize],
+            Internal::Vec(Vec::new(), Vec::new()),
+        );
+        match old {
+            Internal::Vec(leaf_features, leaf_values) => {
+                features.extend(leaf_features);
+                values.extend(leaf_values);
+            }
+            Internal::Map(map) => {
+                for (_, child) in map {
+                    self.take_subtree(child, features, values);
+                }
+            }
+        }
+        self.free.push(internal);
+    }
+
+    /// Builds the subtree at `node` on `level` out of `entries` in one pass.
     ///
-    /// The most significant bit must not be set on the `item`.
+    /// Each entry carries the indices of its feature, so they are only
+    /// computed once. This creates the same shape that inserting the entries
+    /// one at a time would, but no leaf vector is ever converted into a
+    /// `HashMap`.
+    fn build(&mut self, node: usize, level: usize, mut entries: Vec<(F::Indices, F, T)>) {
+        // The last level can't have children since its index is the feature.
+        if entries.len() <= self.config.leaf_split_threshold || level == F::levels() - 1 {
+            let (features, values) = entries
+                .into_iter()
+                .map(|(_, feature, value)| (feature, value))
+                .unzip();
+            self.internals[node] = Internal::Vec(features, values);
+            return;
+        }
+        self.counts[node] = entries.len();
+        entries.sort_unstable_by_key(|&(indices, _, _)| indices.as_ref()[level]);
+        let mut map = InternalMap::default();
+        let mut entries = entries.into_iter().peekable();
+        while let Some(entry) = entries.next() {
+            let tc = entry.0.as_ref()[level];
+            let mut group = vec![entry];
+            while let Some(next) = entries.peek() {
+                if next.0.as_ref()[level] != tc {
+                    break;
+                }
+                group.push(entries.next().unwrap());
+            }
+            let child = self.allocate_internal();
+            map.insert(tc, child);
+            self.build(child as usize, level + 1, group);
+        }
+        self.internals[node] = Internal::Map(map);
+    }
+
+    /// Inserts a `feature` into the `Hwt` along with its associated `value`.
     ///
-    /// Returns `Some(t)` if item `t` was replaced by `item`.
+    /// Inserting a feature that is already present does not replace the
+    /// existing entry. Both entries are kept and both will be found by searches.
     ///
     /// ```
     /// # use hwt::Hwt;
     /// let mut hwt = Hwt::new();
-    /// hwt.insert(0b101);
-    /// hwt.insert(0b010);
-    /// assert_eq!(hwt.len(), 2);
+    /// hwt.insert(0b101, 'a');
+    /// hwt.insert(0b010, 'b');
+    /// hwt.insert(0b010, 'c');
+    /// assert_eq!(hwt.len(), 3);
     /// ```
-    pub fn insert(&mut self, feature: u128) {
+    pub fn insert(&mut self, feature: F, value: T) {
         // No matter what we will insert the item, so increase the count now.
         self.count += 1;
         // Compute the indices of the buckets and the sizes of the buckets
         // for each layer of the tree.
-        let indices = indices128(feature);
+        let indices = feature.indices();
         let mut bucket = 0;
         let mut create_internal = None;
-        for (i, &tc) in indices.iter().enumerate() {
+        for (i, &tc) in indices.as_ref().iter().enumerate() {
             match &mut self.internals[bucket] {
-                Internal::Vec(ref mut v) => {
-                    v.push(feature);
-                    if v.len() > TAU {
+                Internal::Vec(ref mut features, ref mut values) => {
+                    features.push(feature);
+                    values.push(value);
+                    // The last level can't have children since its index is the feature.
+                    if features.len() > self.config.leaf_split_threshold && i != F::levels() - 1 {
                         self.convert(bucket, i);
                     }
                     return;
                 }
                 Internal::Map(ref mut map) => {
+                    // The item will be inserted somewhere below this node.
+                    self.counts[bucket] += 1;
                     match map.get(&tc) {
                         Some(&internal) => {
                             // Go to the next node.
@@ -179,8 +510,11 @@ impl Hwt {
             // Allocate a new internal Vec node.
             let new_internal = self.allocate_internal();
             // Add the item to the new internal Vec.
-            if let Internal::Vec(ref mut v) = self.internals[new_internal as usize] {
-                v.push(feature);
+            if let Internal::Vec(ref mut features, ref mut values) =
+                self.internals[new_internal as usize]
+            {
+                features.push(feature);
+                values.push(value);
             } else {
                 unreachable!("cannot have InternalStore::Map in subtable when just created");
             }
@@ -193,32 +527,117 @@ impl Hwt {
         } else {
             // We are just adding this item to the bottom of the tree in a Vec.
             match self.internals[bucket] {
-                Internal::Vec(ref mut v) => v.push(feature),
+                Internal::Vec(ref mut features, ref mut values) => {
+                    features.push(feature);
+                    values.push(value);
+                }
                 _ => panic!("Can't have InternalStore::Map at bottom of tree"),
             }
         }
     }
 
+    /// Removes an entry with the given `feature` from the `Hwt`.
+    ///
+    /// If several entries share the `feature`, only one of them is removed.
+    ///
+    /// Returns `true` if an entry was removed.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b101, ());
+    /// hwt.insert(0b010, ());
+    /// assert!(hwt.remove(0b101));
+    /// assert!(!hwt.remove(0b101));
+    /// assert!(!hwt.contains(0b101));
+    /// assert_eq!(hwt.len(), 1);
+    /// ```
+    pub fn remove(&mut self, feature: F) -> bool {
+        self.remove_by(feature, |_| true).is_some()
+    }
+
+    /// Removes an entry with the given `feature` whose value satisfies
+    /// `predicate` from the `Hwt`.
+    ///
+    /// If a node in the tree falls well below the size at which it was split
+    /// into a `HashMap`, it is collapsed back into a `Vec` of leaves and the
+    /// internal nodes below it are reused by later insertions.
+    ///
+    /// Returns `Some(value)` with the value of the removed entry if there was one.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b101, 'a');
+    /// hwt.insert(0b101, 'b');
+    /// assert_eq!(hwt.remove_by(0b101, |&v| v == 'b'), Some('b'));
+    /// assert_eq!(hwt.remove_by(0b101, |&v| v == 'b'), None);
+    /// assert_eq!(hwt.len(), 1);
+    /// ```
+    pub fn remove_by(&mut self, feature: F, mut predicate: impl FnMut(&T) -> bool) -> Option<T> {
+        let indices = feature.indices();
+        let indices = indices.as_ref();
+        // Keep track of the `Internal::Map` nodes we pass through on the way down.
+        let mut path = Vec::with_capacity(F::levels());
+        let mut bucket = 0;
+        while let Internal::Map(map) = &self.internals[bucket] {
+            let child = *map.get(&indices[path.len()])? as usize;
+            path.push(bucket);
+            bucket = child;
+        }
+        let depth = path.len();
+        let (value, emptied) = match &mut self.internals[bucket] {
+            Internal::Vec(features, values) => {
+                let ix = features
+                    .iter()
+                    .zip(values.iter())
+                    .position(|(&f, v)| f == feature && predicate(v))?;
+                features.swap_remove(ix);
+                (values.swap_remove(ix), features.is_empty())
+            }
+            _ => unreachable!("we only stop descending once we reach an Internal::Vec"),
+        };
+        self.count -= 1;
+        for &node in &path {
+            self.counts[node] -= 1;
+        }
+        // Remove the leaf vector from its parent if it is empty now (the root stays).
+        if emptied && depth != 0 {
+            if let Internal::Map(map) = &mut self.internals[path[depth - 1]] {
+                map.remove(&indices[depth - 1]);
+            }
+            self.free_internal(bucket as u32);
+        }
+        // Collapse the highest node on the path that has become small enough.
+        let collapse = self.config.leaf_collapse_threshold;
+        if let Some(&node) = path.iter().find(|&&node| self.counts[node] < collapse) {
+            self.collapse(node);
+        }
+        Some(value)
+    }
+
     /// Checks if a feature is in the `Hwt`.
     ///
     /// ```
     /// # use hwt::Hwt;
     /// let mut hwt = Hwt::new();
-    /// hwt.insert(0b101);
-    /// hwt.insert(0b010);
+    /// hwt.insert(0b101, ());
+    /// hwt.insert(0b010, ());
     /// assert!(hwt.contains(0b101));
     /// assert!(hwt.contains(0b010));
     /// assert!(!hwt.contains(0b000));
     /// assert!(!hwt.contains(0b111));
     /// ```
-    pub fn contains(&mut self, feature: u128) -> bool {
+    pub fn contains(&mut self, feature: F) -> bool {
         // Compute the indices of the buckets and the sizes of the buckets
         // for each layer of the tree.
-        let indices = indices128(feature);
+        let indices = feature.indices();
         let mut bucket = 0;
-        for &index in &indices {
+        for &index in indices.as_ref() {
             match &self.internals[bucket] {
-                Internal::Vec(vec) => return vec.iter().cloned().any(|n| n == feature),
+                Internal::Vec(features, _) => {
+                    return features.iter().cloned().any(|n| n == feature)
+                }
                 Internal::Map(map) => {
                     if let Some((_, &internal)) = map.iter().find(|&(&tc, _)| tc == index) {
                         bucket = internal as usize;
@@ -231,6 +650,24 @@ impl Hwt {
         false
     }
 
+    /// Creates a k-nearest neighbor search for `feature`.
+    ///
+    /// This takes care of the scratch space needed by `nearest` for you.
+    /// See `Knn` for the available options.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 0);
+    /// hwt.insert(0b1111, 1);
+    /// let neighbors = hwt.knn(0b1110).run();
+    /// assert_eq!(neighbors[0].feature, 0b1111);
+    /// assert_eq!(neighbors[0].value, &1);
+    /// ```
+    pub fn knn(&self, feature: F) -> Knn<'_, T, F> {
+        Knn::new(self, feature)
+    }
+
     /// Find the nearest neighbors to a feature. This will give the nearest
     /// neighbors first and expand outwards. It will fill `dest` until its full
     /// with nearest neighbors in order or until `max_weight` is reached,
@@ -243,108 +680,262 @@ impl Hwt {
     /// are no guarantees that they are, since we haven't exhausted all
     /// possible locations in the search tree.
     ///
-    /// Returns the slice of filled neighbors. It may consume only
-    /// part of `dest` if less neighbors are found than `dest`. It
+    /// Returns the slice of filled neighbors along with their values. It may
+    /// consume only part of `dest` if less neighbors are found than `dest`. It
     /// stops searching at `max_weight`, but might obtain features
     /// beyond that and still gives them to the user.
-    #[allow(clippy::cognitive_complexity)]
-    pub fn nearest<'a>(
-        &self,
-        feature: u128,
+    ///
+    /// ```
+    /// # use hwt::*;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 0);
+    /// hwt.insert(0b1111, 1);
+    /// let mut node_queue = NodeQueue::new();
+    /// let mut feature_heap = FeatureHeap::new();
+    /// let mut neighbors = [(0, &0); 1];
+    /// let neighbors = hwt.nearest(
+    ///     0b1011,
+    ///     128,
+    ///     0,
+    ///     &mut node_queue,
+    ///     &mut feature_heap,
+    ///     &mut neighbors,
+    /// );
+    /// assert_eq!(neighbors.len(), 1);
+    /// ```
+    pub fn nearest<'a, 'b>(
+        &'a self,
+        feature: F,
         max_weight: u32,
         max_error: u32,
         node_queue: &mut NodeQueue,
         feature_heap: &mut FeatureHeap,
-        dest: &'a mut [u128],
-    ) -> &'a mut [u128] {
-        trace!(
-            "nearest feature({:032X}) weight({})",
+        dest: &'b mut [(F, &'a T)],
+    ) -> &'b mut [(F, &'a T)] {
+        self.nearest_fill(
             feature,
-            feature.count_ones()
-        );
-        let indices;
-        let leaf_distance = |f: &u128| (f ^ feature).count_ones();
-        // Expand the root node.
-        match &self.internals[0] {
-            Internal::Vec(v) => {
-                trace!("nearest sole leaf node len({})", v.len());
-                // Fill dest with as many elements as possible.
-                if dest.len() == 1 {
+            max_weight,
+            max_error,
+            node_queue,
+            feature_heap,
+            dest,
+            None,
+            None,
+            |_, node, ix| self.leaf(node, ix),
+        )
+    }
+
+    /// This is the same as `nearest`, but it also gives the distance of
+    /// each neighbor as `(distance, feature, value)`.
+    ///
+    /// The distances come directly from the search, so no extra work is done
+    /// to compute them.
+    ///
+    /// ```
+    /// # use hwt::*;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 0);
+    /// hwt.insert(0b1111, 1);
+    /// let mut node_queue = NodeQueue::new();
+    /// let mut feature_heap = FeatureHeap::new();
+    /// let mut neighbors = [(0, 0, &0); 2];
+    /// let neighbors = hwt.nearest_with_distance(
+    ///     0b1011,
+    ///     128,
+    ///     0,
+    ///     &mut node_queue,
+    ///     &mut feature_heap,
+    ///     &mut neighbors,
+    /// );
+    /// assert_eq!(neighbors[0].0, 1);
+    /// assert_eq!(neighbors[1].0, 1);
+    /// ```
+    pub fn nearest_with_distance<'a, 'b>(
+        &'a self,
+        feature: F,
+        max_weight: u32,
+        max_error: u32,
+        node_queue: &mut NodeQueue,
+        feature_heap: &mut FeatureHeap,
+        dest: &'b mut [(u32, F, &'a T)],
+    ) -> &'b mut [(u32, F, &'a T)] {
+        self.nearest_fill(
+            feature,
+            max_weight,
+            max_error,
+            node_queue,
+            feature_heap,
+            dest,
+            None,
+            None,
+            |distance, node, ix| {
+                let (feature, value) = self.leaf(node, ix);
+                (distance, feature, value)
+            },
+        )
+    }
+
+    /// This is the same as `nearest_with_distance`, but it also records
+    /// the work done by the search in `stats`.
+    ///
+    /// ```
+    /// # use hwt::*;
+    /// let hwt = Hwt::from_slice(&[0b1001u128, 0b1111]);
+    /// let mut node_queue = NodeQueue::new();
+    /// let mut feature_heap = FeatureHeap::new();
+    /// let mut stats = QueryStats::default();
+    /// let mut neighbors = [(0, 0, &0); 1];
+    /// let neighbors = hwt.nearest_with_stats(
+    ///     0b1011,
+    ///     128,
+    ///     0,
+    ///     &mut node_queue,
+    ///     &mut feature_heap,
+    ///     &mut neighbors,
+    ///     &mut stats,
+    /// );
+    /// assert_eq!(neighbors[0].0, 1);
+    /// assert_eq!(stats.leaves_scanned, 2);
+    /// ```
+    #[allow(clippy::too_many_arguments)]
+    pub fn nearest_with_stats<'a, 'b>(
+        &'a self,
+        feature: F,
+        max_weight: u32,
+        max_error: u32,
+        node_queue: &mut NodeQueue,
+        feature_heap: &mut FeatureHeap,
+        dest: &'b mut [(u32, F, &'a T)],
+        stats: &mut QueryStats,
+    ) -> &'b mut [(u32, F, &'a T)] {
+        self.nearest_fill(
+            feature,
+            max_weight,
+            max_error,
+            node_queue,
+            feature_heap,
+            dest,
+            None,
+            Some(stats),
+            |distance, node, ix| {
+                let (feature, value) = self.leaf(node, ix);
+                (distance, feature, value)
+            },
+        )
+    }
+
+    /// Performs the search for `nearest`, filling `dest` with the output of
+    /// `output(distance, node, index)` for every neighbor found, where
+    /// (node, index) is the location of the leaf in the tree.
+    ///
+    /// Leaves rejected by `filter` are never added to the `FeatureHeap`.
+    /// The work done by the search is recorded in `stats`.
+    #[allow(clippy::cognitive_complexity, clippy::too_many_arguments)]
+    pub(crate) fn nearest_fill<'b, O>(
+        &self,
+        feature: F,
+        max_weight: u32,
+        max_error: u32,
+        node_queue: &mut NodeQueue,
+        feature_heap: &mut FeatureHeap,
+        dest: &'b mut [O],
+        mut filter: Filter<'_, T, F>,
+        mut stats: Option<&mut QueryStats>,
+        output: impl Fn(u32, u32, u32) -> O + Copy,
+    ) -> &'b mut [O] {
+        trace!("nearest feature({:?})", feature);
+        if let Some(stats) = &mut stats {
+            stats.reset();
+        }
+        let feature_indices;
+        let indices = match &self.internals[0] {
+            // Expand the root node.
+            Internal::Vec(features, _) => {
+                trace!("nearest sole leaf node len({})", features.len());
+                if dest.len() == 1 && filter.is_none() {
+                    if let Some(stats) = &mut stats {
+                        stats.leaves_scanned += features.len();
+                    }
                     // In this special case we can get better performance.
-                    return match v.iter().cloned().min_by_key(leaf_distance) {
-                        Some(l) => {
-                            dest[0] = l;
+                    return match features
+                        .iter()
+                        .map(|&f| f.distance(feature))
+                        .enumerate()
+                        .min_by_key(|&(_, distance)| distance)
+                    {
+                        Some((ix, distance)) => {
+                            dest[0] = output(distance, 0, ix as u32);
                             dest
                         }
                         None => [].as_mut(),
                     };
-                } else if v.len() < dest.len() {
-                    let retslice = &mut dest[0..v.len()];
-                    retslice.copy_from_slice(v.as_slice());
-                    retslice.sort_unstable_by_key(leaf_distance);
-                    return retslice;
-                } else {
-                    dest.copy_from_slice(&v[0..dest.len()]);
-                    dest.sort_unstable_by_key(leaf_distance);
-                    for &leaf in v {
-                        let pos = dest
-                            .binary_search_by_key(&leaf_distance(&leaf), leaf_distance)
-                            .unwrap_or_else(|e| e);
-                        if pos != dest.len() {
-                            // It is more nearest neighbor than others.
-                            // Add it to the end.
-                            dest[dest.len() - 1] = leaf;
-                            // Rotate it to the position.
-                            dest[pos..].rotate_right(1);
-                        }
-                    }
+                } else if dest.is_empty() {
                     return dest;
+                } else {
+                    // Fill dest with as many elements as possible.
+                    feature_heap.reset(dest.len(), F::BITS);
+                    self.add_leaves(feature_heap, feature, 0, &mut filter, &mut stats);
+                    return feature_heap.fill_slice(dest, output);
                 }
             }
             Internal::Map(m) => {
                 trace!("nearest emptying root len({})", m.len());
-                indices = indices128(feature);
-                node_queue.clear();
-                feature_heap.reset(dest.len(), feature);
+                feature_indices = feature.indices();
+                let indices = feature_indices.as_ref();
+                node_queue.clear(F::BITS);
+                feature_heap.reset(dest.len(), F::BITS);
                 for (distance, node) in m
                     .iter()
-                    .map(|(&tc, &node)| {
-                        let distance = (tc ^ indices[0]).count_ones();
-                        (distance, node)
-                    })
+                    .map(|(&tc, &node)| (tc.distance(indices[0]), node))
                     .filter(|&(distance, _)| distance <= max_weight)
                 {
-                    match unsafe {
-                        std::mem::transmute::<_, &'static Internal>(&self.internals[node as usize])
-                    } {
-                        Internal::Vec(v) => {
-                            feature_heap.add(v.as_slice());
+                    match &self.internals[node as usize] {
+                        Internal::Vec(..) => {
+                            self.add_leaves(feature_heap, feature, node, &mut filter, &mut stats);
                             if feature_heap.done() {
-                                return feature_heap.fill_slice(dest);
+                                return feature_heap.fill_slice(dest, output);
                             }
                         }
-                        Internal::Map(m) => {
-                            node_queue.add_one((distance, &m, 0));
+                        Internal::Map(_) => {
+                            node_queue.add_one((distance, node, 0));
                         }
                     }
                 }
+                indices
             }
-        }
+        };
 
-        for distance in 0..=max_weight {
+        for distance in 0..=std::cmp::min(max_weight, F::BITS) {
             trace!("searching distance({})", distance);
+            if let Some(stats) = &mut stats {
+                stats.search_distance = distance;
+            }
             // Tell the feature heap we are searching at the max error distance
             // so that once we have found enough features within the error, then
             // we are done.
-            feature_heap.search_distance(std::cmp::min(128, distance + max_error));
+            feature_heap
+                .search_distance(std::cmp::min(F::BITS, distance.saturating_add(max_error)));
             if feature_heap.done() {
-                return feature_heap.fill_slice(dest);
+                return feature_heap.fill_slice(dest, output);
+            }
+            // Every node has been searched, so no more leaves can be found.
+            if node_queue.distance().is_none() {
+                break;
             }
             while node_queue.distance() == Some(distance) {
-                if let Some((_, internal, level)) = node_queue.pop() {
-                    if level == 7 {
-                        unreachable!("hwt: it is impossible to have an internal node at layer 7");
+                if let Some((_, node, level)) = node_queue.pop() {
+                    let level = level as usize;
+                    if level == F::levels() - 1 {
+                        unreachable!(
+                            "hwt: it is impossible to have an internal node at the last level"
+                        );
                     }
+                    let internal = match &self.internals[node as usize] {
+                        Internal::Map(m) => m,
+                        Internal::Vec(..) => {
+                            unreachable!("hwt: only Internal::Map is placed in the NodeQueue")
+                        }
+                    };
 
                     trace!(
                         "nearest node distance({}) len({}) level({})",
@@ -352,413 +943,1216 @@ impl Hwt {
                         internal.len(),
                         level
                     );
-                    if internal.len() < TABLE_TAUS[level as usize] {
-                        trace!("nearest brute force");
-                        for (child_distance, child) in internal.iter().map(|(&tc, &child)| {
-                            let child_distance = (tc ^ indices[(level + 1) as usize]).count_ones();
-                            (child_distance, child)
-                        }) {
-                            match unsafe {
-                                std::mem::transmute::<_, &'static Internal>(
-                                    &self.internals[child as usize],
-                                )
-                            } {
-                                Internal::Vec(leaves) => {
-                                    feature_heap.add(leaves.as_slice());
-                                    if feature_heap.done() {
-                                        return feature_heap.fill_slice(dest);
-                                    }
-                                }
-                                Internal::Map(m) => {
-                                    node_queue.add_one((child_distance, &m, level + 1));
-                                }
-                            }
+                    // Only use a precision search if there are enough children
+                    // to make it worthwhile and the feature supports it.
+                    let precise = self
+                        .config
+                        .precision_search_thresholds
+                        .get(level)
+                        .filter(|&&tau| internal.len() >= tau)
+                        .and_then(|_| internal.keys().next())
+                        .and_then(|&tp| {
+                            F::search_exact(level, indices[level], indices[level + 1], tp, distance)
+                        });
+                    let search_again = precise.is_some();
+                    if let Some(stats) = &mut stats {
+                        stats.pop(level, search_again);
+                    }
+                    let children = match precise {
+                        None => {
+                            trace!("nearest brute force");
+                            either::Left(internal.iter().map(|(&tc, &child)| (tc, Some(child))))
                         }
-                    } else {
-                        trace!("nearest precision search");
-                        match level {
-                            0 => {
-                                let tp = Bits64(*internal.iter().next().unwrap().0).pack_ones();
-                                for Bits64(tc) in search_exact2(
-                                    64,
-                                    Bits128(indices[level as usize]),
-                                    Bits64(indices[level as usize + 1]),
-                                    tp,
-                                    distance,
-                                ) {
-                                    if let Some(&child) = internal.get(&tc) {
-                                        match unsafe {
-                                            std::mem::transmute::<_, &'static Internal>(
-                                                &self.internals[child as usize],
-                                            )
-                                        } {
-                                            Internal::Vec(leaves) => {
-                                                trace!("nearest leaves len({})", leaves.len());
-                                                feature_heap.add(leaves.as_slice());
-                                                if feature_heap.done() {
-                                                    return feature_heap.fill_slice(dest);
-                                                }
-                                            }
-                                            Internal::Map(m) => {
-                                                trace!("nearest map len({})", m.len());
-                                                let child_distance =
-                                                    (tc ^ indices[level as usize + 1]).count_ones();
-                                                node_queue.add_one((child_distance, &m, level + 1));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            1 => {
-                                let tp = Bits32(*internal.iter().next().unwrap().0).pack_ones();
-                                for Bits32(tc) in search_exact4(
-                                    32,
-                                    Bits64(indices[level as usize]),
-                                    Bits32(indices[level as usize + 1]),
-                                    tp,
-                                    distance,
-                                ) {
-                                    if let Some(&child) = internal.get(&tc) {
-                                        match unsafe {
-                                            std::mem::transmute::<_, &'static Internal>(
-                                                &self.internals[child as usize],
-                                            )
-                                        } {
-                                            Internal::Vec(leaves) => {
-                                                trace!("nearest leaves len({})", leaves.len());
-                                                feature_heap.add(leaves.as_slice());
-                                                if feature_heap.done() {
-                                                    return feature_heap.fill_slice(dest);
-                                                }
-                                            }
-                                            Internal::Map(m) => {
-                                                trace!("nearest map len({})", m.len());
-                                                let child_distance =
-                                                    (tc ^ indices[level as usize + 1]).count_ones();
-                                                node_queue.add_one((child_distance, &m, level + 1));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            2 => {
-                                let tp = Bits16(*internal.iter().next().unwrap().0).pack_ones();
-                                for Bits16(tc) in search_exact8(
-                                    16,
-                                    Bits32(indices[level as usize]),
-                                    Bits16(indices[level as usize + 1]),
-                                    tp,
-                                    distance,
-                                ) {
-                                    if let Some(&child) = internal.get(&tc) {
-                                        match unsafe {
-                                            std::mem::transmute::<_, &'static Internal>(
-                                                &self.internals[child as usize],
-                                            )
-                                        } {
-                                            Internal::Vec(leaves) => {
-                                                trace!("nearest leaves len({})", leaves.len());
-                                                feature_heap.add(leaves.as_slice());
-                                                if feature_heap.done() {
-                                                    return feature_heap.fill_slice(dest);
-                                                }
-                                            }
-                                            Internal::Map(m) => {
-                                                trace!("nearest map len({})", m.len());
-                                                let child_distance =
-                                                    (tc ^ indices[level as usize + 1]).count_ones();
-                                                node_queue.add_one((child_distance, &m, level + 1));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            3 => {
-                                let tp = Bits8(*internal.iter().next().unwrap().0).pack_ones();
-                                for Bits8(tc) in search_exact16(
-                                    8,
-                                    Bits16(indices[level as usize]),
-                                    Bits8(indices[level as usize + 1]),
-                                    tp,
-                                    distance,
-                                ) {
-                                    if let Some(&child) = internal.get(&tc) {
-                                        match unsafe {
-                                            std::mem::transmute::<_, &'static Internal>(
-                                                &self.internals[child as usize],
-                                            )
-                                        } {
-                                            Internal::Vec(leaves) => {
-                                                trace!("nearest leaves len({})", leaves.len());
-                                                feature_heap.add(leaves.as_slice());
-                                                if feature_heap.done() {
-                                                    return feature_heap.fill_slice(dest);
-                                                }
-                                            }
-                                            Internal::Map(m) => {
-                                                trace!("nearest map len({})", m.len());
-                                                let child_distance =
-                                                    (tc ^ indices[level as usize + 1]).count_ones();
-                                                node_queue.add_one((child_distance, &m, level + 1));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            4 => {
-                                let tp = Bits4(*internal.iter().next().unwrap().0).pack_ones();
-                                for Bits4(tc) in search_exact32(
-                                    4,
-                                    Bits8(indices[level as usize]),
-                                    Bits4(indices[level as usize + 1]),
-                                    tp,
-                                    distance,
-                                ) {
-                                    if let Some(&child) = internal.get(&tc) {
-                                        match unsafe {
-                                            std::mem::transmute::<_, &'static Internal>(
-                                                &self.internals[child as usize],
-                                            )
-                                        } {
-                                            Internal::Vec(leaves) => {
-                                                trace!("nearest leaves len({})", leaves.len());
-                                                feature_heap.add(leaves.as_slice());
-                                                if feature_heap.done() {
-                                                    return feature_heap.fill_slice(dest);
-                                                }
-                                            }
-                                            Internal::Map(m) => {
-                                                trace!("nearest map len({})", m.len());
-                                                let child_distance =
-                                                    (tc ^ indices[level as usize + 1]).count_ones();
-                                                node_queue.add_one((child_distance, &m, level + 1));
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            5 => {
-                                let tp = Bits2(*internal.iter().next().unwrap().0).pack_ones();
-                                for Bits2(tc) in search_exact64(
-                                    2,
-                                    Bits4(indices[level as usize]),
-                                    Bits2(indices[level as usize + 1]),
-                                    tp,
-                                    distance,
-                                ) {
-                                    if let Some(&child) = internal.get(&tc) {
-                                        match unsafe {
-                                            std::mem::transmute::<_, &'static Internal>(
-                                                &self.internals[child as usize],
-                                            )
-                                        } {
-                                            Internal::Vec(leaves) => {
-                                                trace!("nearest leaves len({})", leaves.len());
-                                                feature_heap.add(leaves.as_slice());
-                                                if feature_heap.done() {
-                                                    return feature_heap.fill_slice(dest);
-                                                }
-                                            }
-                                            Internal::Map(m) => {
-                                                trace!("nearest map len({})", m.len());
-                                                let child_distance =
-                                                    (tc ^ indices[level as usize + 1]).count_ones();
-                                                node_queue.add_one((child_distance, &m, level + 1));
-                                            }
-                                        }
-                                    }
+                        Some(tcs) => {
+                            trace!("nearest precision search");
+                            either::Right(tcs.map(move |tc| (tc, internal.get(&tc).cloned())))
+                        }
+                    };
+                    for (tc, child) in children {
+                        if let (Some(stats), true) = (&mut stats, search_again) {
+                            stats.candidate_indices += 1;
+                            stats.hash_misses += child.is_none() as usize;
+                        }
+                        let child = match child {
+                            Some(child) => child,
+                            None => continue,
+                        };
+                        match &self.internals[child as usize] {
+                            Internal::Vec(leaves, _) => {
+                                trace!("nearest leaves len({})", leaves.len());
+                                self.add_leaves(
+                                    feature_heap,
+                                    feature,
+                                    child,
+                                    &mut filter,
+                                    &mut stats,
+                                );
+                                if feature_heap.done() {
+                                    return feature_heap.fill_slice(dest, output);
                                 }
                             }
-                            6 => {
-                                let tp = Bits1(*internal.iter().next().unwrap().0).pack_ones();
-                                for Bits1(tc) in search_exact128(
-                                    1,
-                                    Bits2(indices[level as usize]),
-                                    Bits1(indices[level as usize + 1]),
-                                    tp,
-                                    distance,
-                                ) {
-                                    if let Some(&child) = internal.get(&tc) {
-                                        match unsafe {
-                                            std::mem::transmute::<_, &'static Internal>(
-                                                &self.internals[child as usize],
-                                            )
-                                        } {
-                                            Internal::Vec(leaves) => {
-                                                trace!("nearest leaves len({})", leaves.len());
-                                                feature_heap.add(leaves.as_slice());
-                                                if feature_heap.done() {
-                                                    return feature_heap.fill_slice(dest);
-                                                }
-                                            }
-                                            Internal::Map(m) => {
-                                                trace!("nearest map len({})", m.len());
-                                                let child_distance =
-                                                    (tc ^ indices[level as usize + 1]).count_ones();
-                                                node_queue.add_one((child_distance, &m, level + 1));
-                                            }
-                                        }
-                                    }
-                                }
+                            Internal::Map(m) => {
+                                trace!("nearest map len({})", m.len());
+                                let child_distance = tc.distance(indices[level + 1]);
+                                node_queue.add_one((child_distance, child, level as u8 + 1));
                             }
-                            _ => unreachable!(),
-                        }
-                        // Add the internal back at the next possible distance.
-                        if distance != 128 {
-                            node_queue.add_one((distance + 1, internal, level));
                         }
                     }
+                    // Add the internal back at the next possible distance if
+                    // only the children at this distance were searched.
+                    if search_again && distance != F::BITS {
+                        node_queue.add_one((distance + 1, node, level as u8));
+                    }
                 }
             }
         }
-        feature_heap.fill_slice(dest)
-    }
-
-    /// Find all neighbors within a given radius.
-    pub fn search_radius<'a>(
-        &'a self,
-        radius: u32,
-        feature: u128,
-    ) -> impl Iterator<Item = u128> + 'a {
-        let index = indices128(feature)[1];
-        // Iterate over every applicable index in the root.
-        self.bucket_scan_radius(radius, feature, 0, Self::radius2, move |tc| {
-            (tc ^ index).count_ones() <= radius
-        })
-    }
-
-    fn radius2<'a>(
-        &'a self,
-        radius: u32,
-        feature: u128,
-        bucket: usize,
-    ) -> impl Iterator<Item = u128> + 'a {
-        let index = indices128(feature)[2];
-        self.bucket_scan_radius(radius, feature, bucket, Self::radius4, move |tc| {
-            (tc ^ index).count_ones() <= radius
-        })
+        feature_heap.fill_slice(dest, output)
     }
 
-    fn radius4<'a>(
-        &'a self,
-        radius: u32,
-        feature: u128,
-        bucket: usize,
-    ) -> impl Iterator<Item = u128> + 'a {
-        let index = indices128(feature)[3];
-        self.bucket_scan_radius(radius, feature, bucket, Self::radius8, move |tc| {
-            (tc ^ index).count_ones() <= radius
-        })
+    /// Adds the leaves of the leaf vector `node` to the search for `feature`,
+    /// skipping any leaves that `filter` rejects.
+    fn add_leaves(
+        &self,
+        feature_heap: &mut FeatureHeap,
+        feature: F,
+        node: u32,
+        filter: &mut Filter<'_, T, F>,
+        stats: &mut Option<&mut QueryStats>,
+    ) {
+        let (features, values) = match &self.internals[node as usize] {
+            Internal::Vec(features, values) => (features, values),
+            Internal::Map(_) => unreachable!("hwt: only Internal::Vec has leaves"),
+        };
+        if let Some(stats) = stats {
+            stats.leaves_scanned += features.len();
+        }
+        match filter {
+            None => feature_heap.add(feature, node, features.as_slice()),
+            Some(filter) => {
+                for (ix, (&leaf, value)) in features.iter().zip(values).enumerate() {
+                    if filter(leaf, value) {
+                        feature_heap.add_one(leaf.distance(feature), (node, ix as u32));
+                    }
+                }
+            }
+        }
     }
 
-    fn radius8<'a>(
-        &'a self,
-        radius: u32,
-        feature: u128,
-        bucket: usize,
-    ) -> impl Iterator<Item = u128> + 'a {
-        let index = indices128(feature)[4];
-        self.bucket_scan_radius(radius, feature, bucket, Self::radius16, move |tc| {
-            (tc ^ index).count_ones() <= radius
-        })
+    /// Finds the `k` nearest neighbors of `feature` for which `predicate`
+    /// returns `true`, nearest first.
+    ///
+    /// Rejected features are skipped during the search, so even if many of
+    /// the nearest features are rejected this still finds the `k` nearest
+    /// features which are accepted. Use `Hwt::knn` and `Knn::run_filtered`
+    /// to configure the search further.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// // The value is the image each feature came from.
+    /// hwt.insert(0b1000, 0);
+    /// hwt.insert(0b1001, 0);
+    /// hwt.insert(0b1111, 1);
+    /// hwt.insert(0b0111, 2);
+    /// let neighbors = hwt.nearest_filtered(0b1000, 2, |_, &image| image != 0);
+    /// assert_eq!(neighbors.len(), 2);
+    /// assert_eq!(neighbors[0].value, &1);
+    /// assert_eq!(neighbors[1].value, &2);
+    /// ```
+    pub fn nearest_filtered(
+        &self,
+        feature: F,
+        k: usize,
+        predicate: impl FnMut(F, &T) -> bool,
+    ) -> Vec<Neighbor<'_, T, F>> {
+        self.knn(feature).k(k).run_filtered(predicate)
     }
 
-    fn radius16<'a>(
+    /// This is the same as `nearest`, but the distance only counts the bits
+    /// which are set in `mask`, so the other bits of the features don't
+    /// matter.
+    ///
+    /// The precision search can't account for the mask, so this checks every
+    /// child of the `HashMap` nodes it expands.
+    ///
+    /// ```
+    /// # use hwt::*;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 0);
+    /// hwt.insert(0b0110, 1);
+    /// let mut node_queue = NodeQueue::new();
+    /// let mut feature_heap = FeatureHeap::new();
+    /// let mut neighbors = [(0, &0); 1];
+    /// // Only the lowest two bits are compared.
+    /// let neighbors = hwt.nearest_masked(
+    ///     0b1110,
+    ///     0b0011,
+    ///     128,
+    ///     0,
+    ///     &mut node_queue,
+    ///     &mut feature_heap,
+    ///     &mut neighbors,
+    /// );
+    /// assert_eq!(neighbors[0], (0b0110, &1));
+    /// ```
+    #[allow(clippy::too_many_arguments)]
+    pub fn nearest_masked<'a, 'b>(
         &'a self,
-        radius: u32,
-        feature: u128,
-        bucket: usize,
-    ) -> impl Iterator<Item = u128> + 'a {
-        let index = indices128(feature)[5];
-        self.bucket_scan_radius(radius, feature, bucket, Self::radius32, move |tc| {
-            (tc ^ index).count_ones() <= radius
-        })
+        feature: F,
+        mask: F,
+        max_weight: u32,
+        max_error: u32,
+        node_queue: &mut NodeQueue,
+        feature_heap: &mut FeatureHeap,
+        dest: &'b mut [(F, &'a T)],
+    ) -> &'b mut [(F, &'a T)] {
+        trace!("nearest_masked feature({:?}) mask({:?})", feature, mask);
+        let output = |_, node, ix| self.leaf(node, ix);
+        if dest.is_empty() {
+            return dest;
+        }
+        let feature_indices = feature.indices();
+        let ignored_indices = mask.invert().indices();
+        let (indices, ignored) = (feature_indices.as_ref(), ignored_indices.as_ref());
+        node_queue.clear(F::BITS);
+        feature_heap.reset(dest.len(), F::BITS);
+        // Everything could be at any distance from the root.
+        node_queue.add_one((0, 0, 0));
+        for distance in 0..=std::cmp::min(max_weight, F::BITS) {
+            feature_heap.search_distance(std::cmp::min(F::BITS, distance + max_error));
+            if feature_heap.done() {
+                break;
+            }
+            while node_queue.distance() == Some(distance) {
+                let (_, node, level) = node_queue.pop().unwrap();
+                let level = level as usize;
+                match &self.internals[node as usize] {
+                    Internal::Vec(features, _) => {
+                        for (ix, &leaf) in features.iter().enumerate() {
+                            let distance = leaf.distance_masked(feature, mask);
+                            feature_heap.add_one(distance, (node, ix as u32));
+                        }
+                        if feature_heap.done() {
+                            return feature_heap.fill_slice(dest, output);
+                        }
+                    }
+                    Internal::Map(m) => {
+                        for (&tc, &child) in m {
+                            let child_distance =
+                                F::min_distance_masked(level, tc, indices[level], ignored[level]);
+                            if child_distance <= max_weight {
+                                node_queue.add_one((child_distance, child, level as u8 + 1));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        feature_heap.fill_slice(dest, output)
     }
 
-    fn radius32<'a>(
+    /// Finds the nearest neighbors under a weighted hamming distance, where
+    /// each bit that differs adds its weight from `weights` to the distance
+    /// (see `HammingFeature::distance_weighted`). This fills `dest` with
+    /// `(distance, feature, value)` nearest first.
+    ///
+    /// The tree is searched using the smallest weight in each substring to
+    /// bound the distance of the leaves under a node, so the search is exact
+    /// but it gets slower the more the weights in a substring differ.
+    /// Neighbors further than `max_weight` are never found.
+    ///
+    /// ```
+    /// # use hwt::*;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b0011, 0);
+    /// hwt.insert(0b0100, 1);
+    /// let mut node_queue = NodeQueue::new();
+    /// let mut feature_heap = FeatureHeap::new();
+    /// let mut weights = [1; 128];
+    /// // The lowest bit counts 5 times as much as the others.
+    /// weights[0] = 5;
+    /// let mut neighbors = [(0, 0, &0); 2];
+    /// let neighbors = hwt.nearest_weighted(
+    ///     0b0010,
+    ///     &weights,
+    ///     u32::max_value(),
+    ///     &mut node_queue,
+    ///     &mut feature_heap,
+    ///     &mut neighbors,
+    /// );
+    /// assert_eq!(neighbors, [(2, 0b0100, &1), (5, 0b0011, &0)]);
+    /// ```
+    pub fn nearest_weighted<'a, 'b>(
+        &'a self,
+        feature: F,
+        weights: &[u8],
+        max_weight: u32,
+        node_queue: &mut NodeQueue,
+        feature_heap: &mut FeatureHeap,
+        dest: &'b mut [(u32, F, &'a T)],
+    ) -> &'b mut [(u32, F, &'a T)] {
+        assert_eq!(
+            weights.len(),
+            F::BITS as usize,
+            "hwt: there must be a weight for every bit"
+        );
+        trace!("nearest_weighted feature({:?})", feature);
+        let output = |distance, node, ix| {
+            let (feature, value) = self.leaf(node, ix);
+            (distance, feature, value)
+        };
+        if dest.is_empty() {
+            return dest;
+        }
+        let feature_indices = feature.indices();
+        let indices = feature_indices.as_ref();
+        // The smallest weight in every substring at every level.
+        let min_weights = (0..F::levels())
+            .map(|level| {
+                weights
+                    .chunks(F::BITS as usize >> level)
+                    .map(|substring| u32::from(*substring.iter().min().unwrap()))
+                    .collect::<Vec<u32>>()
+            })
+            .collect::<Vec<_>>();
+        let max_distance = std::cmp::min(
+            max_weight,
+            weights.iter().map(|&weight| u32::from(weight)).sum(),
+        );
+        node_queue.clear(max_distance);
+        feature_heap.reset(dest.len(), max_distance);
+        // Everything could be at any distance from the root.
+        node_queue.add_one((0, 0, 0));
+        while let Some(distance) = node_queue.distance() {
+            // Nothing closer than the closest node remains to be found.
+            feature_heap.search_distance(distance);
+            if feature_heap.done() {
+                break;
+            }
+            let (_, node, level) = node_queue.pop().unwrap();
+            let level = level as usize;
+            match &self.internals[node as usize] {
+                Internal::Vec(features, _) => {
+                    for (ix, &leaf) in features.iter().enumerate() {
+                        let distance = leaf.distance_weighted(feature, weights);
+                        if distance <= max_distance {
+                            feature_heap.add_one(distance, (node, ix as u32));
+                        }
+                    }
+                }
+                Internal::Map(m) => {
+                    for (&tc, &child) in m {
+                        let child_distance = F::min_distance_weighted(
+                            level,
+                            tc,
+                            indices[level],
+                            &min_weights[level],
+                        );
+                        if child_distance <= max_distance {
+                            node_queue.add_one((child_distance, child, level as u8 + 1));
+                        }
+                    }
+                }
+            }
+        }
+        feature_heap.fill_slice(dest, output)
+    }
+
+    /// Find all neighbors within a given radius.
+    ///
+    /// Yields the features along with their values in no particular order.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0110, 'c');
+    /// let mut neighbors = hwt.search_radius(1, 0b1000).collect::<Vec<_>>();
+    /// neighbors.sort_unstable();
+    /// assert_eq!(neighbors, vec![(0b1000, &'b'), (0b1001, &'a')]);
+    /// ```
+    pub fn search_radius<'a>(
         &'a self,
         radius: u32,
-        feature: u128,
-        bucket: usize,
-    ) -> impl Iterator<Item = u128> + 'a {
-        let index = indices128(feature)[6];
-        self.bucket_scan_radius(radius, feature, bucket, Self::radius64, move |tc| {
-            (tc ^ index).count_ones() <= radius
-        })
+        feature: F,
+    ) -> impl Iterator<Item = (F, &'a T)> + 'a {
+        self.search_radius_with_distance(radius, feature)
+            .map(|(_, feature, value)| (feature, value))
     }
 
-    fn radius64<'a>(
+    /// This is the same as `search_radius`, but it also gives the distance of
+    /// each neighbor as `(distance, feature, value)`.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0110, 'c');
+    /// let mut neighbors = hwt
+    ///     .search_radius_with_distance(1, 0b1000)
+    ///     .collect::<Vec<_>>();
+    /// neighbors.sort_unstable();
+    /// assert_eq!(neighbors, vec![(0, 0b1000, &'b'), (1, 0b1001, &'a')]);
+    /// ```
+    pub fn search_radius_with_distance<'a>(
         &'a self,
         radius: u32,
-        feature: u128,
-        bucket: usize,
-    ) -> impl Iterator<Item = u128> + 'a {
-        let index = indices128(feature)[7];
-        self.bucket_scan_radius(radius, feature, bucket, Self::radius128, move |tc| {
-            (tc ^ index).count_ones() <= radius
-        })
+        feature: F,
+    ) -> impl Iterator<Item = (u32, F, &'a T)> + 'a {
+        self.bucket_scan_range(0, radius, feature, feature.indices(), 0, 0)
+    }
+
+    /// Find all neighbors with a distance in `range`, such as `20..=30`.
+    ///
+    /// Yields `(distance, feature, value)` in no particular order. Subtrees
+    /// which are entirely closer than the start of the range are skipped.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0111, 'c');
+    /// let mut neighbors = hwt.search_range(0b1000, 1..=4).collect::<Vec<_>>();
+    /// neighbors.sort_unstable();
+    /// assert_eq!(neighbors, vec![(1, 0b1001, &'a'), (4, 0b0111, &'c')]);
+    /// ```
+    pub fn search_range<'a>(
+        &'a self,
+        feature: F,
+        range: RangeInclusive<u32>,
+    ) -> impl Iterator<Item = (u32, F, &'a T)> + 'a {
+        let (min, max) = range.into_inner();
+        self.bucket_scan_range(min, max, feature, feature.indices(), 0, 0)
+    }
+
+    /// Find all neighbors exactly `distance` away from `feature`.
+    ///
+    /// Yields the features along with their values in no particular order.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0111, 'c');
+    /// let neighbors = hwt.search_exact_distance(0b1000, 1).collect::<Vec<_>>();
+    /// assert_eq!(neighbors, vec![(0b1001, &'a')]);
+    /// ```
+    pub fn search_exact_distance<'a>(
+        &'a self,
+        feature: F,
+        distance: u32,
+    ) -> impl Iterator<Item = (F, &'a T)> + 'a {
+        self.search_range(feature, distance..=distance)
+            .map(|(_, feature, value)| (feature, value))
     }
 
-    fn radius128<'a>(
+    /// Find all neighbors within a given radius and append them to `dest`
+    /// as `(distance, feature, value)` in order of distance, closest first.
+    ///
+    /// The neighbors are sorted into a bucket for every distance in the
+    /// `FeatureHeap`, so it can be reused between searches to avoid
+    /// allocating. This also avoids the boxed iterators that
+    /// `search_radius` needs at every level of the tree.
+    ///
+    /// ```
+    /// # use hwt::*;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0111, 'c');
+    /// let mut feature_heap = FeatureHeap::new();
+    /// let mut neighbors = vec![];
+    /// hwt.search_radius_sorted(4, 0b1000, &mut feature_heap, &mut neighbors);
+    /// assert_eq!(
+    ///     neighbors,
+    ///     vec![(0, 0b1000, &'b'), (1, 0b1001, &'a'), (4, 0b0111, &'c')]
+    /// );
+    /// ```
+    pub fn search_radius_sorted<'a>(
         &'a self,
         radius: u32,
-        feature: u128,
-        bucket: usize,
-    ) -> impl Iterator<Item = u128> + 'a {
-        self.bucket_scan_radius(
+        feature: F,
+        feature_heap: &mut FeatureHeap,
+        dest: &mut Vec<(u32, F, &'a T)>,
+    ) {
+        // Nothing is ever evicted from the heap with an unlimited cap.
+        feature_heap.reset(usize::MAX, F::BITS);
+        self.visit_radius(
             radius,
             feature,
-            bucket,
-            |_, _, _, bucket| -> Box<dyn Iterator<Item = u128> + 'a> {
-                panic!(
-                    "hwt::Hwt::neighbors128(): it is an error to find an internal node this far down in the tree (bucket: {})", bucket, 
-                )
-            },
-            move |tc| panic!("hwt::Hwt::neighbors128(): it is an error to find an internal node this far down in the tree (tc: {})", tc)
-        )
+            &feature.indices(),
+            0,
+            0,
+            &mut |distance, node, ix| feature_heap.add_one(distance, (node, ix)),
+        );
+        dest.extend(feature_heap.leaves().map(|(distance, node, ix)| {
+            let (feature, value) = self.leaf(node, ix);
+            (distance, feature, value)
+        }));
+    }
+
+    /// Counts the features within `radius` of `feature`.
+    ///
+    /// This is the same as `search_radius(radius, feature).count()`, but
+    /// whole subtrees are counted at once when every feature in them is
+    /// known to be within the radius.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0110, 'c');
+    /// assert_eq!(hwt.count_within(1, 0b1000), 2);
+    /// assert_eq!(hwt.count_within(128, 0b1000), 3);
+    /// ```
+    pub fn count_within(&self, radius: u32, feature: F) -> usize {
+        self.count_within_capped(radius, feature, usize::MAX)
     }
 
-    /// Search the given `bucket` with the `indices` iterator, using `subtable`
-    /// to recursively iterate over buckets found inside this bucket.
+    /// This is the same as `count_within`, but it stops counting once `cap`
+    /// features are found and returns `cap`.
+    ///
+    /// This is useful to check if there are at least `cap` features within
+    /// the radius without finding all of them.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0110, 'c');
+    /// assert_eq!(hwt.count_within_capped(128, 0b1000, 2), 2);
+    /// assert_eq!(hwt.count_within_capped(0, 0b1000, 2), 1);
+    /// ```
+    pub fn count_within_capped(&self, radius: u32, feature: F, cap: usize) -> usize {
+        let mut count = 0;
+        self.bucket_count_radius(radius, feature, &feature.indices(), 0, 0, cap, &mut count);
+        std::cmp::min(count, cap)
+    }
+
+    /// Adds the amount of leaves within `radius` in the given `bucket` at
+    /// `level` of the tree to `count`, stopping once it reaches `cap`.
     #[allow(clippy::too_many_arguments)]
-    fn bucket_scan_radius<'a, I: 'a>(
+    fn bucket_count_radius(
+        &self,
+        radius: u32,
+        feature: F,
+        indices: &F::Indices,
+        bucket: usize,
+        level: usize,
+        cap: usize,
+        count: &mut usize,
+    ) {
+        match &self.internals[bucket] {
+            Internal::Vec(features, _) => {
+                *count += features
+                    .iter()
+                    .filter(|&&leaf| leaf.distance(feature) <= radius)
+                    .count();
+            }
+            Internal::Map(m) => {
+                let index = indices.as_ref()[level];
+                let child = |tc: F, node: u32, count: &mut usize| {
+                    if F::max_distance(level, tc, index) <= radius {
+                        // Every leaf in the child is within the radius.
+                        *count += match &self.internals[node as usize] {
+                            Internal::Vec(features, _) => features.len(),
+                            Internal::Map(_) => self.counts[node as usize],
+                        };
+                    } else {
+                        self.bucket_count_radius(
+                            radius,
+                            feature,
+                            indices,
+                            node as usize,
+                            level + 1,
+                            cap,
+                            count,
+                        );
+                    }
+                };
+                match precise_radius(
+                    &self.config.precision_search_thresholds,
+                    m.len(),
+                    m.keys().next(),
+                    radius,
+                    *indices,
+                    level,
+                ) {
+                    Some(tcs) => {
+                        for tc in tcs {
+                            if *count >= cap {
+                                return;
+                            }
+                            if let Some(&node) = m.get(&tc) {
+                                child(tc, node, count);
+                            }
+                        }
+                    }
+                    None => {
+                        for (&tc, &node) in m {
+                            if *count >= cap {
+                                return;
+                            }
+                            if tc.distance(index) <= radius {
+                                child(tc, node, count);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `visit(distance, node, index)` for every leaf within `radius`
+    /// in the given `bucket` at `level` of the tree.
+    fn visit_radius(
+        &self,
+        radius: u32,
+        feature: F,
+        indices: &F::Indices,
+        bucket: usize,
+        level: usize,
+        visit: &mut impl FnMut(u32, u32, u32),
+    ) {
+        match &self.internals[bucket] {
+            Internal::Vec(features, _) => {
+                for (ix, &leaf) in features.iter().enumerate() {
+                    let distance = leaf.distance(feature);
+                    if distance <= radius {
+                        visit(distance, bucket as u32, ix as u32);
+                    }
+                }
+            }
+            Internal::Map(m) => {
+                match precise_radius(
+                    &self.config.precision_search_thresholds,
+                    m.len(),
+                    m.keys().next(),
+                    radius,
+                    *indices,
+                    level,
+                ) {
+                    Some(tcs) => {
+                        for tc in tcs {
+                            if let Some(&node) = m.get(&tc) {
+                                self.visit_radius(
+                                    radius,
+                                    feature,
+                                    indices,
+                                    node as usize,
+                                    level + 1,
+                                    visit,
+                                );
+                            }
+                        }
+                    }
+                    None => {
+                        for (&tc, &node) in m {
+                            if tc.distance(indices.as_ref()[level]) <= radius {
+                                self.visit_radius(
+                                    radius,
+                                    feature,
+                                    indices,
+                                    node as usize,
+                                    level + 1,
+                                    visit,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// This is the same as `search_radius`, but the distance only counts the
+    /// bits which are set in `mask`, so the other bits of the features don't
+    /// matter.
+    ///
+    /// The precision search can't account for the mask, so this checks every
+    /// child of the `HashMap` nodes it visits.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0110, 'c');
+    /// // Only the highest two of the lowest four bits are compared.
+    /// let mut neighbors = hwt.search_radius_masked(0, 0b1011, 0b1100).collect::<Vec<_>>();
+    /// neighbors.sort_unstable();
+    /// assert_eq!(neighbors, vec![(0b1000, &'b'), (0b1001, &'a')]);
+    /// ```
+    pub fn search_radius_masked<'a>(
         &'a self,
         radius: u32,
-        feature: u128,
+        feature: F,
+        mask: F,
+    ) -> impl Iterator<Item = (F, &'a T)> + 'a {
+        let ignored = mask.invert().indices();
+        self.bucket_scan_masked(radius, feature, mask, feature.indices(), ignored, 0, 0)
+    }
+
+    /// Search the given `bucket` at `level` of the tree for leaves within
+    /// `radius` when only counting the bits set in `mask`, where `ignored`
+    /// are the indices of the bits not set in `mask`.
+    #[allow(clippy::too_many_arguments)]
+    fn bucket_scan_masked<'a>(
+        &'a self,
+        radius: u32,
+        feature: F,
+        mask: F,
+        indices: F::Indices,
+        ignored: F::Indices,
         bucket: usize,
-        subtable: fn(&'a Self, u32, u128, usize) -> I,
-        filter: impl Fn(u128) -> bool + 'a,
-    ) -> Box<dyn Iterator<Item = u128> + 'a>
-    where
-        I: Iterator<Item = u128>,
-    {
-        trace!(
-            "bucket_scan_radius feature({:032X}) radius({}) bucket({})",
-            feature,
-            radius,
-            bucket,
-        );
-        let lookup_distance = move |leaf: u128| (leaf ^ feature).count_ones();
+        level: usize,
+    ) -> Box<dyn Iterator<Item = (F, &'a T)> + 'a> {
         match &self.internals[bucket] {
-            Internal::Vec(v) => Box::new(
-                v.iter()
+            Internal::Vec(features, values) => Box::new(
+                features
+                    .iter()
                     .cloned()
-                    .filter(move |&leaf| lookup_distance(leaf) <= radius),
+                    .zip(values.iter())
+                    .filter(move |&(leaf, _)| leaf.distance_masked(feature, mask) <= radius),
             ),
             Internal::Map(m) => Box::new(
                 m.iter()
-                    .filter(move |&(&key, _)| filter(key))
-                    .flat_map(move |(_, &node)| subtable(self, radius, feature, node as usize)),
+                    .filter(move |&(&tc, _)| {
+                        let (index, ignored) = (indices.as_ref()[level], ignored.as_ref()[level]);
+                        F::min_distance_masked(level, tc, index, ignored) <= radius
+                    })
+                    .flat_map(move |(_, &node)| {
+                        self.bucket_scan_masked(
+                            radius,
+                            feature,
+                            mask,
+                            indices,
+                            ignored,
+                            node as usize,
+                            level + 1,
+                        )
+                    }),
             ),
         }
     }
+
+    /// Search the given `bucket` at `level` of the tree for leaves whose
+    /// distance is in `min..=max`, recursively iterating over the buckets
+    /// found inside this bucket.
+    fn bucket_scan_range<'a>(
+        &'a self,
+        min: u32,
+        max: u32,
+        feature: F,
+        indices: F::Indices,
+        bucket: usize,
+        level: usize,
+    ) -> Box<dyn Iterator<Item = (u32, F, &'a T)> + 'a> {
+        trace!(
+            "bucket_scan_range feature({:?}) min({}) max({}) bucket({})",
+            feature,
+            min,
+            max,
+            bucket,
+        );
+        match &self.internals[bucket] {
+            Internal::Vec(features, values) => Box::new(
+                features
+                    .iter()
+                    .zip(values.iter())
+                    .map(move |(&leaf, value)| (leaf.distance(feature), leaf, value))
+                    .filter(move |&(distance, _, _)| distance >= min && distance <= max),
+            ),
+            Internal::Map(m) => {
+                let index = indices.as_ref()[level];
+                // Children which are entirely closer than `min` are skipped.
+                let reaches_min =
+                    move |&tc: &F| min == 0 || F::max_distance(level, tc, index) >= min;
+                let recurse = move |&node: &u32| {
+                    self.bucket_scan_range(min, max, feature, indices, node as usize, level + 1)
+                };
+                match precise_radius(
+                    &self.config.precision_search_thresholds,
+                    m.len(),
+                    m.keys().next(),
+                    max,
+                    indices,
+                    level,
+                ) {
+                    Some(tcs) => {
+                        trace!("bucket_scan_range precision search len({})", m.len());
+                        Box::new(
+                            tcs.into_iter()
+                                .filter(reaches_min)
+                                .filter_map(move |tc| m.get(&tc))
+                                .flat_map(recurse),
+                        )
+                    }
+                    None => {
+                        trace!("bucket_scan_range brute force len({})", m.len());
+                        Box::new(
+                            m.iter()
+                                .filter(move |&(tc, _)| {
+                                    tc.distance(index) <= max && reaches_min(tc)
+                                })
+                                .flat_map(move |(_, node)| recurse(node)),
+                        )
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the `Hwt` to `writer` in a compact binary format which can be
+    /// loaded with `read_from`.
+    ///
+    /// The format starts with a version header and ends with a checksum.
+    /// This writes a lot of small pieces, so `writer` should be buffered.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b101, 'a' as u32);
+    /// hwt.insert(0b010, 'b' as u32);
+    /// let mut bytes = vec![];
+    /// hwt.write_to(&mut bytes).unwrap();
+    /// let loaded: Hwt<u32> = Hwt::read_from(&mut bytes.as_slice()).unwrap();
+    /// assert_eq!(loaded.search_radius(0, 0b101).next(), Some((0b101, &('a' as u32))));
+    /// ```
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()>
+    where
+        T: BinaryValue,
+    {
+        let mut writer = Checksummed::new(writer);
+        writer.write_all(&MAGIC)?;
+        VERSION.write_value(&mut writer)?;
+        F::BITS.write_value(&mut writer)?;
+        self.config.write_to(&mut writer)?;
+        self.count.write_value(&mut writer)?;
+        self.internals.len().write_value(&mut writer)?;
+        for (internal, count) in self.internals.iter().zip(&self.counts) {
+            match internal {
+                Internal::Vec(features, values) => {
+                    0u8.write_value(&mut writer)?;
+                    features.len().write_value(&mut writer)?;
+                    for feature in features {
+                        feature.write_value(&mut writer)?;
+                    }
+                    for value in values {
+                        value.write_value(&mut writer)?;
+                    }
+                }
+                Internal::Map(map) => {
+                    1u8.write_value(&mut writer)?;
+                    count.write_value(&mut writer)?;
+                    map.len().write_value(&mut writer)?;
+                    for (tc, node) in map {
+                        tc.write_value(&mut writer)?;
+                        node.write_value(&mut writer)?;
+                    }
+                }
+            }
+        }
+        self.free.len().write_value(&mut writer)?;
+        for node in &self.free {
+            node.write_value(&mut writer)?;
+        }
+        let checksum = writer.checksum();
+        checksum.write_value(&mut writer.inner)
+    }
+
+    /// Reads a `Hwt` written by `write_to` from `reader`.
+    ///
+    /// The tree keeps the config it was written with.
+    ///
+    /// Returns an error of kind `InvalidData` if the input was written by
+    /// an incompatible version, has a different feature width, is corrupt,
+    /// or isn't a `Hwt` at all.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self>
+    where
+        T: BinaryValue,
+    {
+        let mut reader = Checksummed::new(reader);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("hwt: input is not a stored Hwt"));
+        }
+        if u32::read_value(&mut reader)? != VERSION {
+            return Err(invalid_data("hwt: unsupported format version"));
+        }
+        if u32::read_value(&mut reader)? != F::BITS {
+            return Err(invalid_data("hwt: stored features have a different width"));
+        }
+        let config = HwtConfig::read_from(&mut reader)?;
+        let count = usize::read_value(&mut reader)?;
+        let (nodes, prealloc) = read_len(&mut reader)?;
+        let mut internals = Vec::with_capacity(prealloc);
+        let mut counts = Vec::with_capacity(prealloc);
+        for _ in 0..nodes {
+            match u8::read_value(&mut reader)? {
+                0 => {
+                    let (len, prealloc) = read_len(&mut reader)?;
+                    let mut features = Vec::with_capacity(prealloc);
+                    let mut values = Vec::with_capacity(prealloc);
+                    for _ in 0..len {
+                        features.push(F::read_value(&mut reader)?);
+                    }
+                    for _ in 0..len {
+                        values.push(T::read_value(&mut reader)?);
+                    }
+                    internals.push(Internal::Vec(features, values));
+                    counts.push(0);
+                }
+                1 => {
+                    counts.push(usize::read_value(&mut reader)?);
+                    let (len, prealloc) = read_len(&mut reader)?;
+                    let mut map = InternalMap::default();
+                    map.reserve(prealloc);
+                    for _ in 0..len {
+                        let tc = F::read_value(&mut reader)?;
+                        let node = u32::read_value(&mut reader)?;
+                        if node as usize >= nodes {
+                            return Err(invalid_data("hwt: node out of bounds"));
+                        }
+                        map.insert(tc, node);
+                    }
+                    internals.push(Internal::Map(map));
+                }
+                _ => return Err(invalid_data("hwt: unknown node kind")),
+            }
+        }
+        let (len, prealloc) = read_len(&mut reader)?;
+        let mut free = Vec::with_capacity(prealloc);
+        for _ in 0..len {
+            let node = u32::read_value(&mut reader)?;
+            if node as usize >= nodes {
+                return Err(invalid_data("hwt: node out of bounds"));
+            }
+            free.push(node);
+        }
+        let checksum = reader.checksum();
+        if u64::read_value(&mut reader.inner)? != checksum {
+            return Err(invalid_data("hwt: checksum mismatch"));
+        }
+        let hwt = Self {
+            internals,
+            counts,
+            free,
+            count,
+            config,
+        };
+        hwt.check().map_err(invalid_data)?;
+        Ok(hwt)
+    }
+
+    /// Checks that a loaded tree has the shape of one built by `insert`, so
+    /// that corrupt input can't make a search recurse forever or panic.
+    ///
+    /// Every node must either be free or be reachable from the root exactly
+    /// once, a `HashMap` can't be on the last level, and the amount of leaves
+    /// under every `HashMap` and in the whole tree must be right.
+    fn check(&self) -> Result<(), &'static str> {
+        if self.internals.is_empty() || self.counts.len() != self.internals.len() {
+            return Err("hwt: inconsistent tree");
+        }
+        let mut seen = vec![false; self.internals.len()];
+        for &node in &self.free {
+            match self.internals.get(node as usize) {
+                Some(Internal::Vec(features, _)) if features.is_empty() && !seen[node as usize] => {
+                    seen[node as usize] = true;
+                }
+                _ => return Err("hwt: invalid free node"),
+            }
+        }
+        if self.check_node(0, 0, &mut seen)? != self.count || seen.contains(&false) {
+            return Err("hwt: inconsistent tree");
+        }
+        Ok(())
+    }
+
+    /// Checks the subtree at `node` on `level` for `check`, marking the nodes
+    /// in it as `seen`.
+    ///
+    /// Returns the amount of leaves in the subtree.
+    fn check_node(
+        &self,
+        node: u32,
+        level: usize,
+        seen: &mut [bool],
+    ) -> Result<usize, &'static str> {
+        let node = node as usize;
+        match seen.get_mut(node) {
+            Some(seen) if !*seen => *seen = true,
+            Some(_) => return Err("hwt: node is reachable more than once"),
+            None => return Err("hwt: node out of bounds"),
+        }
+        match &self.internals[node] {
+            Internal::Vec(features, values) => {
+                if features.len() != values.len() {
+                    return Err("hwt: inconsistent tree");
+                }
+                Ok(features.len())
+            }
+            Internal::Map(map) => {
+                // The last level can't have children since its index is the feature.
+                if level >= F::levels() - 1 {
+                    return Err("hwt: HashMap on the last level");
+                }
+                let mut leaves = 0;
+                for &child in map.values() {
+                    leaves += self.check_node(child, level + 1, seen)?;
+                }
+                if leaves != self.counts[node] {
+                    return Err("hwt: inconsistent tree");
+                }
+                Ok(leaves)
+            }
+        }
+    }
+
+    /// Creates a read-only copy of the `Hwt` stored in one flat buffer,
+    /// which can be written to a file and memory-mapped later.
+    ///
+    /// Also gives the values of the tree in the order of the leaves in the
+    /// `FrozenHwt`, so that the value of a leaf found by a search is at the
+    /// position of the leaf.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0110, 'c');
+    /// let (frozen, values) = hwt.freeze();
+    /// let mut neighbors = frozen
+    ///     .search_radius(1, 0b1000)
+    ///     .map(|(feature, leaf)| (feature, values[leaf]))
+    ///     .collect::<Vec<_>>();
+    /// neighbors.sort_unstable();
+    /// assert_eq!(neighbors, vec![(0b1000, &'b'), (0b1001, &'a')]);
+    /// ```
+    pub fn freeze(&self) -> (FrozenHwt<Vec<u8>, F>, Vec<&T>) {
+        // The nodes are renumbered in the order they are found so that
+        // the freed nodes are left out.
+        let mut order = vec![0];
+        let mut nodes = vec![];
+        let mut values = Vec::with_capacity(self.count);
+        let mut next = 0;
+        while next < order.len() {
+            match &self.internals[order[next] as usize] {
+                Internal::Vec(features, leaf_values) => {
+                    values.extend(leaf_values);
+                    nodes.push(FrozenNode::Leaves(features));
+                }
+                Internal::Map(map) => {
+                    let mut children = map
+                        .iter()
+                        .map(|(&tc, &child)| {
+                            order.push(child);
+                            (tc, order.len() as u32 - 1)
+                        })
+                        .collect::<Vec<_>>();
+                    children.sort_unstable_by_key(|&(tc, _)| tc);
+                    nodes.push(FrozenNode::Map(children));
+                }
+            }
+            next += 1;
+        }
+        let frozen = FrozenHwt::new(freeze_nodes(
+            &nodes,
+            &self.config.precision_search_thresholds,
+        ))
+        .expect("hwt: a frozen Hwt must always be valid");
+        (frozen, values)
+    }
+
+    /// Expands the internal `node` at `level` of the tree for a best-first
+    /// search of `feature`. Its children are added to `node_queue` at the
+    /// smallest distance any of their leaves could have, and its leaves are
+    /// passed to `leaf(distance, node, index)`.
+    pub(crate) fn expand(
+        &self,
+        feature: F,
+        indices: &[F],
+        node: u32,
+        level: u8,
+        node_queue: &mut NodeQueue,
+        mut leaf: impl FnMut(u32, u32, u32),
+    ) {
+        match &self.internals[node as usize] {
+            Internal::Vec(features, _) => {
+                for (ix, &f) in features.iter().enumerate() {
+                    leaf(f.distance(feature), node, ix as u32);
+                }
+            }
+            Internal::Map(m) => {
+                for (&tc, &child) in m {
+                    let distance = tc.distance(indices[level as usize]);
+                    node_queue.add_one((distance, child, level + 1));
+                }
+            }
+        }
+    }
+
+    /// Creates an iterator over every feature in order of its distance from
+    /// `feature`, closest first.
+    ///
+    /// The search is only performed as far as the iterator is advanced, so
+    /// this is useful when it isn't known ahead of time how many neighbors
+    /// are needed. It yields `(distance, feature, value)`.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1000, 'a');
+    /// hwt.insert(0b1011, 'b');
+    /// hwt.insert(0b1111, 'c');
+    /// let mut neighbors = hwt.nearest_iter(0b1001);
+    /// assert_eq!(neighbors.next().unwrap().0, 1);
+    /// assert_eq!(neighbors.next().unwrap().0, 1);
+    /// assert_eq!(neighbors.next(), Some((2, 0b1111, &'c')));
+    /// assert_eq!(neighbors.next(), None);
+    /// // Stop at the first neighbor which passes a check.
+    /// let b = hwt.nearest_iter(0b1001).find(|&(_, _, &value)| value == 'b');
+    /// assert_eq!(b, Some((1, 0b1011, &'b')));
+    /// ```
+    pub fn nearest_iter(&self, feature: F) -> NearestIter<'_, T, F> {
+        NearestIter::new(self, feature)
+    }
+
+    /// Gets the feature and value at index `ix` of the leaf node `node`.
+    pub(crate) fn leaf(&self, node: u32, ix: u32) -> (F, &T) {
+        match &self.internals[node as usize] {
+            Internal::Vec(features, values) => (features[ix as usize], &values[ix as usize]),
+            Internal::Map(_) => unreachable!("hwt: leaves can only be found in Internal::Vec"),
+        }
+    }
+
+    /// Iterates over the `(node, ix)` locations of every leaf along with its
+    /// feature and value, in the same order as `iter`.
+    pub(crate) fn iter_leaves(&self) -> impl Iterator<Item = (u32, u32, F, &T)> {
+        self.internals
+            .iter()
+            .zip(0..)
+            .flat_map(|(internal, node)| match internal {
+                Internal::Vec(features, values) => either::Left(
+                    features
+                        .iter()
+                        .cloned()
+                        .zip(values)
+                        .zip(0..)
+                        .map(move |((feature, value), ix)| (node, ix, feature, value)),
+                ),
+                Internal::Map(_) => either::Right(std::iter::empty()),
+            })
+    }
+}
+
+/// Gets the children indices within `radius` of the search indices in
+/// a `HashMap` at `level` with `len` children with a precision search if
+/// it has at least as many children as its `thresholds`. `tc` is any of the
+/// children indices.
+///
+/// With a large radius there can be far more possible children than are
+/// actually in the `HashMap`, so this gives up on the precision search if it
+/// finds more than that. The root has no parent index, so it always uses
+/// brute force.
+pub(crate) fn precise_radius<F: HammingFeature>(
+    thresholds: &[usize; 7],
+    len: usize,
+    tc: Option<&F>,
+    radius: u32,
+    indices: F::Indices,
+    level: usize,
+) -> Option<Vec<F>> {
+    let parent = level.checked_sub(1)?;
+    if len < *thresholds.get(parent)? {
+        return None;
+    }
+    let indices = indices.as_ref();
+    let tcs = F::search_radius(parent, indices[parent], indices[level], *tc?, radius)?
+        .take(len + 1)
+        .collect::<Vec<F>>();
+    if tcs.len() > len {
+        None
+    } else {
+        Some(tcs)
+    }
+}
+
+impl<F: HammingFeature> Hwt<usize, F> {
+    /// Builds an `Hwt` out of a slice of features all at once, where the
+    /// value of each feature is its index in the slice.
+    ///
+    /// This is much faster than inserting the features one at a time.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let hwt = Hwt::from_slice(&[0b1001u128, 0b1111]);
+    /// assert_eq!(hwt.len(), 2);
+    /// assert_eq!(hwt.knn(0b1110).run()[0].value, &1);
+    /// ```
+    pub fn from_slice(features: &[F]) -> Self {
+        features.iter().cloned().zip(0..).collect()
+    }
+}
+
+impl<T, F: HammingFeature> std::iter::FromIterator<(F, T)> for Hwt<T, F> {
+    /// Builds an `Hwt` out of (feature, value) pairs all at once.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let hwt: Hwt<char> = vec![(0b1001, 'a'), (0b1111, 'b')].into_iter().collect();
+    /// assert_eq!(hwt.len(), 2);
+    /// ```
+    fn from_iter<I: IntoIterator<Item = (F, T)>>(iter: I) -> Self {
+        let mut hwt = Self::default();
+        hwt.extend(iter);
+        hwt
+    }
+}
+
+impl<T, F: HammingFeature> Extend<(F, T)> for Hwt<T, F> {
+    /// Adds (feature, value) pairs to the `Hwt`.
+    ///
+    /// If the `Hwt` is empty, the tree is built all at once like `from_slice`.
+    /// Otherwise this is the same as inserting each pair.
+    fn extend<I: IntoIterator<Item = (F, T)>>(&mut self, iter: I) {
+        if self.is_empty() {
+            let entries = iter
+                .into_iter()
+                .map(|(feature, value)| (feature.indices(), feature, value))
+                .collect::<Vec<_>>();
+            // Removals can leave empty nodes behind, so start from scratch.
+            self.internals = vec![Internal::Vec(Vec::new(), Vec::new())];
+            self.counts = vec![0];
+            self.free = vec![];
+            self.count = entries.len();
+            self.build(0, 0, entries);
+        } else {
+            for (feature, value) in iter {
+                self.insert(feature, value);
+            }
+        }
+    }
 }
 
-impl Default for Hwt {
+impl<T, F> Default for Hwt<T, F> {
     fn default() -> Self {
+        let config = HwtConfig::default();
         Self {
-            internals: vec![Internal::default()],
+            internals: vec![Internal::with_capacity(config.initial_leaf_capacity)],
+            counts: vec![0],
+            free: vec![],
             count: 0,
+            config,
         }
     }
 }
@@ -0,0 +1,166 @@
+//! Finding every pair of features within a radius of each other, either in
+//! one `Hwt` or between two of them.
+//!
+//! Both trees are walked at the same time. Two subtrees at the same level
+//! are only searched if the distance between their indices is within the
+//! radius, since that is the smallest distance any two features under them
+//! can have. Once either side is a leaf vector, each of its leaves does a
+//! radius search of the other subtree.
+
+use super::{Hwt, Internal};
+use crate::matching::Match;
+use crate::HammingFeature;
+
+impl<T, F: HammingFeature> Hwt<T, F> {
+    /// Finds every pair of features in the `Hwt` within `radius` of each
+    /// other, such as near-duplicates.
+    ///
+    /// Each pair is only found once, in no particular order. Entries are
+    /// never paired with themselves, but two entries with the same feature
+    /// are a pair.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let hwt = Hwt::from_slice(&[0b0000u128, 0b1111, 0b0001, 0b0111]);
+    /// let mut pairs = hwt
+    ///     .self_join(1)
+    ///     .iter()
+    ///     .map(|m| (*m.a.min(m.b), *m.a.max(m.b)))
+    ///     .collect::<Vec<_>>();
+    /// pairs.sort_unstable();
+    /// assert_eq!(pairs, vec![(0, 2), (1, 3)]);
+    /// ```
+    pub fn self_join(&self, radius: u32) -> Vec<Match<&T, &T>> {
+        let mut matches = vec![];
+        self.self_join_node(radius, 0, 0, &mut matches);
+        matches
+    }
+
+    /// Finds every pair of a feature in this `Hwt` and a feature in `other`
+    /// within `radius` of each other, in no particular order.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let a = Hwt::from_slice(&[0b0000u128, 0b1111]);
+    /// let b = Hwt::from_slice(&[0b0111u128, 0b0001, 0b1000]);
+    /// let mut pairs = a
+    ///     .join(&b, 1)
+    ///     .iter()
+    ///     .map(|m| (*m.a, *m.b))
+    ///     .collect::<Vec<_>>();
+    /// pairs.sort_unstable();
+    /// assert_eq!(pairs, vec![(0, 1), (0, 2), (1, 0)]);
+    /// ```
+    pub fn join<'a, 'b, U>(
+        &'a self,
+        other: &'b Hwt<U, F>,
+        radius: u32,
+    ) -> Vec<Match<&'a T, &'b U>> {
+        let mut matches = vec![];
+        self.join_nodes(other, radius, 0, 0, 0, &mut matches);
+        matches
+    }
+
+    /// Finds the pairs within the subtree of `node` at `level`.
+    fn self_join_node<'a>(
+        &'a self,
+        radius: u32,
+        node: u32,
+        level: usize,
+        matches: &mut Vec<Match<&'a T, &'a T>>,
+    ) {
+        match &self.internals[node as usize] {
+            Internal::Vec(features, values) => {
+                for (ix, (&a, va)) in features.iter().zip(values).enumerate() {
+                    for (&b, vb) in features[ix + 1..].iter().zip(&values[ix + 1..]) {
+                        let distance = a.distance(b);
+                        if distance <= radius {
+                            matches.push(Match {
+                                distance,
+                                a: va,
+                                b: vb,
+                            });
+                        }
+                    }
+                }
+            }
+            Internal::Map(m) => {
+                let children = m
+                    .iter()
+                    .map(|(&tc, &child)| (tc, child))
+                    .collect::<Vec<_>>();
+                for (ix, &(ta, a)) in children.iter().enumerate() {
+                    self.self_join_node(radius, a, level + 1, matches);
+                    // Every other pair of children is only visited once.
+                    for &(tb, b) in &children[ix + 1..] {
+                        if ta.distance(tb) <= radius {
+                            self.join_nodes(self, radius, a, b, level + 1, matches);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Finds the pairs between the subtree of node `a` in this `Hwt` and
+    /// node `b` in `other`, which are both at `level`.
+    fn join_nodes<'a, 'b, U>(
+        &'a self,
+        other: &'b Hwt<U, F>,
+        radius: u32,
+        a: u32,
+        b: u32,
+        level: usize,
+        matches: &mut Vec<Match<&'a T, &'b U>>,
+    ) {
+        match (&self.internals[a as usize], &other.internals[b as usize]) {
+            (Internal::Map(ma), Internal::Map(mb)) => {
+                for (&ta, &ca) in ma {
+                    for (&tb, &cb) in mb {
+                        if ta.distance(tb) <= radius {
+                            self.join_nodes(other, radius, ca, cb, level + 1, matches);
+                        }
+                    }
+                }
+            }
+            (Internal::Vec(features, values), _) => {
+                for (&feature, va) in features.iter().zip(values) {
+                    let indices = feature.indices();
+                    other.visit_radius(
+                        radius,
+                        feature,
+                        &indices,
+                        b as usize,
+                        level,
+                        &mut |distance, node, ix| {
+                            matches.push(Match {
+                                distance,
+                                a: va,
+                                b: other.leaf(node, ix).1,
+                            });
+                        },
+                    );
+                }
+            }
+            (Internal::Map(_), Internal::Vec(features, values)) => {
+                for (&feature, vb) in features.iter().zip(values) {
+                    let indices = feature.indices();
+                    self.visit_radius(
+                        radius,
+                        feature,
+                        &indices,
+                        a as usize,
+                        level,
+                        &mut |distance, node, ix| {
+                            matches.push(Match {
+                                distance,
+                                a: self.leaf(node, ix).1,
+                                b: vb,
+                            });
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
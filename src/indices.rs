@@ -1,4 +1,4 @@
-use swar::*;
+use crate::bits::*;
 
 /// Compute the indices for a 128-bit integer,
 /// along with the overall `MAX - MIN`.
@@ -0,0 +1,206 @@
+//! An ergonomic interface for k-nearest neighbor searches.
+//!
+//! `Hwt::nearest` requires the caller to own and pass in the scratch space
+//! used during the search. This is ideal for hot loops, but it is cumbersome
+//! otherwise. `Knn` keeps a `NodeQueue` and `FeatureHeap` for each thread
+//! and reuses them for every search on that thread.
+
+use crate::hwt::Filter;
+use crate::{FeatureHeap, HammingFeature, Hwt, NodeQueue, QueryStats};
+use std::cell::RefCell;
+
+/// The scratch space used to perform a search.
+#[derive(Default)]
+struct Scratch {
+    node_queue: NodeQueue,
+    feature_heap: FeatureHeap,
+    /// The (distance, node, index) of each neighbor found.
+    leaves: Vec<(u32, u32, u32)>,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::default());
+}
+
+/// A neighbor found by a search.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Neighbor<'a, T, F = u128> {
+    /// The hamming distance from the search feature.
+    pub distance: u32,
+    /// The feature of the neighbor.
+    pub feature: F,
+    /// The value associated with the feature.
+    pub value: &'a T,
+}
+
+impl<'a, T, F: Copy> Clone for Neighbor<'a, T, F> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T, F: Copy> Copy for Neighbor<'a, T, F> {}
+
+/// A k-nearest neighbor search builder created by `Hwt::knn`.
+///
+/// ```
+/// # use hwt::Hwt;
+/// let mut hwt = Hwt::new();
+/// hwt.insert(0b1000, 'a');
+/// hwt.insert(0b1011, 'b');
+/// hwt.insert(0b1111, 'c');
+/// let neighbors = hwt.knn(0b1001).k(2).max_distance(40).run();
+/// assert_eq!(neighbors.len(), 2);
+/// assert_eq!(neighbors[0].distance, 1);
+/// assert_eq!(neighbors[1].distance, 1);
+/// ```
+pub struct Knn<'a, T, F = u128> {
+    hwt: &'a Hwt<T, F>,
+    feature: F,
+    k: usize,
+    max_distance: u32,
+    max_error: u32,
+}
+
+impl<'a, T, F: HammingFeature> Knn<'a, T, F> {
+    pub(crate) fn new(hwt: &'a Hwt<T, F>, feature: F) -> Self {
+        Self {
+            hwt,
+            feature,
+            k: 1,
+            max_distance: F::BITS,
+            max_error: 0,
+        }
+    }
+
+    /// Sets the amount of neighbors to find. Defaults to `1`.
+    pub fn k(self, k: usize) -> Self {
+        Self { k, ..self }
+    }
+
+    /// Sets the maximum distance of the neighbors found. Defaults to the
+    /// number of bits in the feature, which is also the most it can be.
+    ///
+    /// Neighbors further than this are never returned.
+    pub fn max_distance(self, max_distance: u32) -> Self {
+        Self {
+            max_distance: std::cmp::min(max_distance, F::BITS),
+            ..self
+        }
+    }
+
+    /// Sets the amount of error allowed in the distance of the neighbors.
+    /// Defaults to `0`, which always gives the exact nearest neighbors.
+    ///
+    /// See `Hwt::nearest` for more details.
+    pub fn max_error(self, max_error: u32) -> Self {
+        Self { max_error, ..self }
+    }
+
+    /// Performs the search, returning the neighbors nearest first.
+    pub fn run(self) -> Vec<Neighbor<'a, T, F>> {
+        let mut dest = Vec::with_capacity(self.k);
+        self.run_into(&mut dest);
+        dest
+    }
+
+    /// Performs the search, appending the neighbors to `dest` nearest first.
+    ///
+    /// If `dest` has enough capacity, this doesn't allocate once this
+    /// thread has performed a search of the same size.
+    pub fn run_into(self, dest: &mut Vec<Neighbor<'a, T, F>>) {
+        self.run_with(dest, None, None);
+    }
+
+    /// Performs the search, only finding neighbors for which `predicate`
+    /// returns `true`, and returns them nearest first.
+    ///
+    /// Rejected features are skipped during the search rather than removed
+    /// afterwards, so this still finds `k` neighbors if there are enough
+    /// accepted features.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1000, 'a');
+    /// hwt.insert(0b1011, 'b');
+    /// hwt.insert(0b1111, 'c');
+    /// // Exclude the search feature itself.
+    /// let neighbors = hwt.knn(0b1000).run_filtered(|feature, _| feature != 0b1000);
+    /// assert_eq!(neighbors[0].value, &'b');
+    /// ```
+    pub fn run_filtered(self, mut predicate: impl FnMut(F, &T) -> bool) -> Vec<Neighbor<'a, T, F>> {
+        let mut dest = Vec::with_capacity(self.k);
+        self.run_with(&mut dest, Some(&mut predicate), None);
+        dest
+    }
+
+    /// Performs the search, returning the neighbors nearest first, and
+    /// records the work done by the search in `stats`.
+    ///
+    /// See `QueryStats` for what is recorded.
+    pub fn run_with_stats(self, stats: &mut QueryStats) -> Vec<Neighbor<'a, T, F>> {
+        let mut dest = Vec::with_capacity(self.k);
+        self.run_with(&mut dest, None, Some(stats));
+        dest
+    }
+
+    fn run_with(
+        self,
+        dest: &mut Vec<Neighbor<'a, T, F>>,
+        filter: Filter<'_, T, F>,
+        stats: Option<&mut QueryStats>,
+    ) {
+        if self.k == 0 {
+            return;
+        }
+        SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+            Ok(mut scratch) => self.run_scratch(&mut scratch, dest, filter, stats),
+            // This thread is already in the middle of a search, so we need
+            // to create new scratch space for this one.
+            Err(_) => self.run_scratch(&mut Scratch::default(), dest, filter, stats),
+        });
+    }
+
+    fn run_scratch(
+        self,
+        scratch: &mut Scratch,
+        dest: &mut Vec<Neighbor<'a, T, F>>,
+        filter: Filter<'_, T, F>,
+        stats: Option<&mut QueryStats>,
+    ) {
+        let Scratch {
+            node_queue,
+            feature_heap,
+            leaves,
+        } = scratch;
+        leaves.clear();
+        leaves.resize(self.k, (0, 0, 0));
+        let hwt = self.hwt;
+        let found = hwt.nearest_fill(
+            self.feature,
+            self.max_distance,
+            self.max_error,
+            node_queue,
+            feature_heap,
+            leaves,
+            filter,
+            stats,
+            |distance, node, ix| (distance, node, ix),
+        );
+        let max_distance = self.max_distance;
+        dest.extend(
+            found
+                .iter()
+                .filter(|&&(distance, _, _)| distance <= max_distance)
+                .map(|&(distance, node, ix)| {
+                    let (feature, value) = hwt.leaf(node, ix);
+                    Neighbor {
+                        distance,
+                        feature,
+                        value,
+                    }
+                }),
+        );
+    }
+}
@@ -140,11 +140,11 @@
 //!
 //! 1. `TL` is going towards `SL` and `TR` is going towards `SR` (slope `-2`).
 //! 2. `TL` hits its its inflection point first and starts going away from `SL`
-//!     and `TR` is still going towards `SR` (slope `0`).
+//!    and `TR` is still going towards `SR` (slope `0`).
 //! 3. `TR` hits its its inflection point first and starts going away from `SR`
-//!     and `TL` is still going towards `SL` (slope `0`).
+//!    and `TL` is still going towards `SL` (slope `0`).
 //! 4. `TL` and `TR` have both hit their inflection points and are going away
-//!     from `SL` and `SR` respectively (slope `2`).
+//!    from `SL` and `SR` respectively (slope `2`).
 //!
 //! As we can see, regardless of whether `TL` or `TR` hit their inflection
 //! point first, we can be guaranteed that the slope is `0` before the final
@@ -292,12 +292,32 @@
 //! algorithm will make us test all of those places in the space if they have
 //! tables in the tree.
 
+mod binary;
+pub mod bits;
+mod config;
+mod feature;
 mod feature_heap;
+mod frozen;
 mod hamming_queue;
 mod hwt;
 pub mod indices;
+mod knn;
+pub mod matching;
+mod nearest_iter;
+#[cfg(feature = "rayon")]
+mod par;
+mod popcount;
 pub mod search;
+mod stats;
+pub mod tune;
 
 pub use crate::hwt::*;
+pub use binary::BinaryValue;
+pub use config::{ConfigError, HwtConfig};
+pub use feature::*;
 pub use feature_heap::*;
+pub use frozen::FrozenHwt;
 pub use hamming_queue::*;
+pub use knn::*;
+pub use nearest_iter::NearestIter;
+pub use stats::{QueryStats, TreeStats};
@@ -0,0 +1,152 @@
+//! Matching features between two sets, such as the descriptors of two images.
+//!
+//! Nearest neighbors alone make a lot of bad matches, so these are filtered
+//! in one of two common ways:
+//!
+//! - `mutual_nearest` only keeps matches where both features are the nearest
+//!   neighbor of each other (cross-checking).
+//! - `ratio_match` only keeps matches where the nearest neighbor is much
+//!   closer than the second nearest neighbor (Lowe's ratio test).
+//!
+//! A `Hwt` built with `Hwt::from_slice` stores the index of every feature as
+//! its value, so the matches found with it are pairs of indices.
+
+use crate::{FeatureHeap, HammingFeature, Hwt, NodeQueue};
+
+/// A match between a feature from each of two sets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match<A, B> {
+    /// The hamming distance between the two features.
+    pub distance: u32,
+    /// The feature from the first set.
+    pub a: A,
+    /// The feature from the second set.
+    pub b: B,
+}
+
+/// The scratch space used to perform the searches of a matcher.
+#[derive(Default)]
+struct Scratch {
+    node_queue: NodeQueue,
+    feature_heap: FeatureHeap,
+}
+
+impl Scratch {
+    /// Finds up to two nearest neighbors of `feature` in `hwt` as
+    /// (distance, node, index).
+    fn nearest<'a, T, F: HammingFeature>(
+        &mut self,
+        hwt: &Hwt<T, F>,
+        feature: F,
+        dest: &'a mut [(u32, u32, u32)],
+    ) -> &'a mut [(u32, u32, u32)] {
+        hwt.nearest_fill(
+            feature,
+            F::BITS,
+            0,
+            &mut self.node_queue,
+            &mut self.feature_heap,
+            dest,
+            None,
+            None,
+            |distance, node, ix| (distance, node, ix),
+        )
+    }
+}
+
+/// Finds every pair of features from `a` and `b` where each feature is the
+/// nearest neighbor of the other.
+///
+/// The matches give the values of the features in each `Hwt`. If a feature
+/// is equally near to several others, only one of them is considered its
+/// nearest neighbor.
+///
+/// ```
+/// # use hwt::Hwt;
+/// use hwt::matching::{mutual_nearest, Match};
+/// let a = Hwt::from_slice(&[0b0000u128, 0b1111, 0b0011]);
+/// let b = Hwt::from_slice(&[0b1110u128, 0b0001]);
+/// let mut matches = mutual_nearest(&a, &b);
+/// matches.sort_unstable_by_key(|m| *m.a);
+/// assert_eq!(
+///     matches,
+///     vec![
+///         Match { distance: 1, a: &0, b: &1 },
+///         Match { distance: 1, a: &1, b: &0 },
+///     ]
+/// );
+/// ```
+pub fn mutual_nearest<'a, 'b, T, U, F: HammingFeature>(
+    a: &'a Hwt<T, F>,
+    b: &'b Hwt<U, F>,
+) -> Vec<Match<&'a T, &'b U>> {
+    let mut scratch = Scratch::default();
+    let mut matches = vec![];
+    for (node_a, ix_a, fa, va) in a.iter_leaves() {
+        let mut forward = [(0, 0, 0); 1];
+        let (distance, node, ix) = match scratch.nearest(b, fa, &mut forward).first() {
+            Some(&neighbor) => neighbor,
+            None => break,
+        };
+        let (fb, vb) = b.leaf(node, ix);
+        let mut backward = [(0, 0, 0); 1];
+        if let Some(&(_, node, ix)) = scratch.nearest(a, fb, &mut backward).first() {
+            // It must be the same leaf in the tree, not just an equal feature.
+            if (node, ix) == (node_a, ix_a) {
+                matches.push(Match {
+                    distance,
+                    a: va,
+                    b: vb,
+                });
+            }
+        }
+    }
+    matches
+}
+
+/// Matches every feature in `queries` to its nearest neighbor in `hwt` if
+/// it passes Lowe's ratio test.
+///
+/// A match passes if the distance to the nearest neighbor is less than
+/// `ratio` times the distance to the second nearest neighbor, so that
+/// ambiguous matches are rejected. A ratio of `0.8` is typical. If `hwt` only
+/// has one feature, every match passes.
+///
+/// The matches give the index of the feature in `queries` and the value of
+/// its neighbor in `hwt`.
+///
+/// ```
+/// # use hwt::Hwt;
+/// use hwt::matching::{ratio_match, Match};
+/// let hwt = Hwt::from_slice(&[0b0000_0000u128, 0b1111_0000, 0b1111_1111]);
+/// // The second query is just as near to the first two features.
+/// let matches = ratio_match(&[0b1110_0000, 0b1100_0000], &hwt, 0.8);
+/// assert_eq!(matches, vec![Match { distance: 1, a: 0, b: &1 }]);
+/// ```
+pub fn ratio_match<'a, T, F: HammingFeature>(
+    queries: &[F],
+    hwt: &'a Hwt<T, F>,
+    ratio: f32,
+) -> Vec<Match<usize, &'a T>> {
+    let mut scratch = Scratch::default();
+    let mut matches = vec![];
+    for (query, &feature) in queries.iter().enumerate() {
+        let mut neighbors = [(0, 0, 0); 2];
+        let found = scratch.nearest(hwt, feature, &mut neighbors);
+        let (distance, node, ix) = match found.first() {
+            Some(&best) => best,
+            None => break,
+        };
+        if let Some(&(second, _, _)) = found.get(1) {
+            if distance as f32 >= ratio * second as f32 {
+                continue;
+            }
+        }
+        matches.push(Match {
+            distance,
+            a: query,
+            b: hwt.leaf(node, ix).1,
+        });
+    }
+    matches
+}
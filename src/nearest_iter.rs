@@ -0,0 +1,77 @@
+//! A lazy best-first search created by `Hwt::nearest_iter`.
+//!
+//! Internal nodes are kept in a `NodeQueue` at the smallest distance that any
+//! of their leaves could have. Leaves are kept in a bucket for every distance
+//! once their node is expanded. A leaf can be yielded as soon as no internal
+//! node in the queue could contain anything closer, so nodes are only
+//! expanded as far as the iterator is advanced.
+
+use crate::{HammingFeature, Hwt, NodeQueue};
+
+/// An iterator over the features of a `Hwt` in order of their distance from
+/// a search feature, created by `Hwt::nearest_iter`.
+///
+/// It yields `(distance, feature, value)`.
+pub struct NearestIter<'a, T, F: HammingFeature = u128> {
+    hwt: &'a Hwt<T, F>,
+    feature: F,
+    indices: F::Indices,
+    node_queue: NodeQueue,
+    /// The (node, index) of the leaves found at every distance.
+    leaves: Vec<Vec<(u32, u32)>>,
+    /// No leaves are left at a distance lower than this.
+    lowest: usize,
+}
+
+impl<'a, T, F: HammingFeature> NearestIter<'a, T, F> {
+    pub(crate) fn new(hwt: &'a Hwt<T, F>, feature: F) -> Self {
+        let mut node_queue = NodeQueue::new();
+        node_queue.clear(F::BITS);
+        // Everything could be at any distance from the root.
+        node_queue.add_one((0, 0, 0));
+        Self {
+            hwt,
+            feature,
+            indices: feature.indices(),
+            node_queue,
+            leaves: vec![vec![]; F::BITS as usize + 1],
+            lowest: 0,
+        }
+    }
+}
+
+impl<'a, T, F: HammingFeature> Iterator for NearestIter<'a, T, F> {
+    type Item = (u32, F, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Nothing closer than the closest internal node can be found
+            // by expanding more nodes.
+            let bound = self
+                .node_queue
+                .distance()
+                .map(|distance| distance as usize)
+                .unwrap_or(self.leaves.len());
+            while self.lowest < self.leaves.len() {
+                if let Some((node, ix)) = self.leaves[self.lowest].pop() {
+                    let (feature, value) = self.hwt.leaf(node, ix);
+                    return Some((self.lowest as u32, feature, value));
+                }
+                if self.lowest >= bound {
+                    break;
+                }
+                self.lowest += 1;
+            }
+            let (_, node, level) = self.node_queue.pop()?;
+            let leaves = &mut self.leaves;
+            self.hwt.expand(
+                self.feature,
+                self.indices.as_ref(),
+                node,
+                level,
+                &mut self.node_queue,
+                |distance, node, ix| leaves[distance as usize].push((node, ix)),
+            );
+        }
+    }
+}
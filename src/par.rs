@@ -0,0 +1,62 @@
+//! Searches of many features at once spread across threads with rayon.
+//!
+//! An `Hwt` is never modified while searching, so it can be shared by every
+//! thread. Each thread reuses its own scratch space, just like `Knn` does.
+
+use crate::{HammingFeature, Hwt, Neighbor};
+use rayon::prelude::*;
+
+impl<T, F> Hwt<T, F>
+where
+    T: Sync,
+    F: HammingFeature,
+{
+    /// Finds the `k` nearest neighbors of every feature in `features` in
+    /// parallel, nearest first.
+    ///
+    /// `max_error` is the same as in `Hwt::nearest`. The neighbors of each
+    /// feature are at the same position in the output as the feature.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1111, 'b');
+    /// let neighbors = hwt.nearest_batch(&[0b1000, 0b1110], 1, 0);
+    /// assert_eq!(neighbors[0][0].value, &'a');
+    /// assert_eq!(neighbors[1][0].value, &'b');
+    /// ```
+    pub fn nearest_batch(
+        &self,
+        features: &[F],
+        k: usize,
+        max_error: u32,
+    ) -> Vec<Vec<Neighbor<'_, T, F>>> {
+        features
+            .par_iter()
+            .map(|&feature| self.knn(feature).k(k).max_error(max_error).run())
+            .collect()
+    }
+
+    /// Finds all neighbors within `radius` of every feature in `features`
+    /// in parallel.
+    ///
+    /// The neighbors of each feature are at the same position in the output
+    /// as the feature and are in no particular order.
+    ///
+    /// ```
+    /// # use hwt::Hwt;
+    /// let mut hwt = Hwt::new();
+    /// hwt.insert(0b1001, 'a');
+    /// hwt.insert(0b1000, 'b');
+    /// hwt.insert(0b0110, 'c');
+    /// let neighbors = hwt.par_search_radius(0, &[0b1000, 0b0110]);
+    /// assert_eq!(neighbors, vec![vec![(0b1000, &'b')], vec![(0b0110, &'c')]]);
+    /// ```
+    pub fn par_search_radius(&self, radius: u32, features: &[F]) -> Vec<Vec<(F, &T)>> {
+        features
+            .par_iter()
+            .map(|&feature| self.search_radius(radius, feature).collect())
+            .collect()
+    }
+}
@@ -0,0 +1,154 @@
+//! Computes the hamming distances of many 128-bit features at once.
+//!
+//! The fastest way to do this depends on the instructions the CPU supports,
+//! so the best implementation is detected at runtime the first time it is
+//! needed and used for the rest of the process. This means a binary built
+//! for any x86_64 CPU still gets the fast paths on the CPUs that have them.
+//!
+//! - AVX-512 `VPOPCNTDQ` counts the ones of eight 64-bit words at once.
+//! - AVX2 counts the ones of every nibble with a lookup table in a shuffle
+//!   and then sums the bytes of each feature.
+//! - `POPCNT` counts the ones of one 64-bit word at a time.
+//! - Without any of these, the ones are counted in portable code.
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::sync::OnceLock;
+
+/// Computes the distance from `search` to every feature in `features`,
+/// writing them to the same position in `distances`.
+type Kernel = unsafe fn(search: u128, features: &[u128], distances: &mut [u8]);
+
+/// The `Kernel` chosen for this CPU, which is set the first time it is needed.
+static KERNEL: OnceLock<Kernel> = OnceLock::new();
+
+/// Computes the distance from `search` to every feature in `features`,
+/// writing them to the same position in `distances`.
+///
+/// `distances` must be at least as long as `features`.
+#[inline]
+pub(crate) fn distances128(search: u128, features: &[u128], distances: &mut [u8]) {
+    assert!(distances.len() >= features.len());
+    let kernel = *KERNEL.get_or_init(detect);
+    // The kernel was only chosen if the CPU supports it.
+    unsafe { kernel(search, features, distances) }
+}
+
+/// Gets every `Kernel` this CPU supports along with its name, best first.
+pub(crate) fn kernels() -> Vec<(&'static str, Kernel)> {
+    let mut kernels: Vec<(&'static str, Kernel)> = vec![];
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vpopcntdq") {
+            kernels.push(("avx512", distances_avx512));
+        }
+        if is_x86_feature_detected!("avx2") {
+            kernels.push(("avx2", distances_avx2));
+        }
+        if is_x86_feature_detected!("popcnt") {
+            kernels.push(("popcnt", distances_popcnt));
+        }
+    }
+    kernels.push(("scalar", distances_scalar));
+    kernels
+}
+
+/// Chooses the best `Kernel` for this CPU.
+fn detect() -> Kernel {
+    let (name, kernel) = kernels()[0];
+    log::debug!("hwt popcount kernel({})", name);
+    kernel
+}
+
+fn distances_scalar(search: u128, features: &[u128], distances: &mut [u8]) {
+    for (&feature, distance) in features.iter().zip(distances) {
+        *distance = (feature ^ search).count_ones() as u8;
+    }
+}
+
+/// This is the same as the scalar version, but it is compiled so that
+/// `count_ones` is a single instruction.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "popcnt")]
+unsafe fn distances_popcnt(search: u128, features: &[u128], distances: &mut [u8]) {
+    distances_scalar(search, features, distances)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn distances_avx2(search: u128, features: &[u128], distances: &mut [u8]) {
+    // The amount of ones in every nibble.
+    let lookup = _mm256_setr_epi8(
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4, 0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3,
+        3, 4,
+    );
+    let nibble = _mm256_set1_epi8(0x0f);
+    let (low, high) = (search as i64, (search >> 64) as i64);
+    let search_pair = _mm256_setr_epi64x(low, high, low, high);
+    let pairs = features.chunks_exact(2);
+    let rest = pairs.remainder();
+    let mut pair_distances = distances.chunks_exact_mut(2);
+    for (pair, out) in pairs.zip(&mut pair_distances) {
+        let x = _mm256_xor_si256(
+            _mm256_loadu_si256(pair.as_ptr() as *const __m256i),
+            search_pair,
+        );
+        let counts = _mm256_add_epi8(
+            _mm256_shuffle_epi8(lookup, _mm256_and_si256(x, nibble)),
+            _mm256_shuffle_epi8(lookup, _mm256_and_si256(_mm256_srli_epi16(x, 4), nibble)),
+        );
+        // Sums the bytes of each 64-bit word.
+        let sums = _mm256_sad_epu8(counts, _mm256_setzero_si256());
+        let mut words = [0u64; 4];
+        _mm256_storeu_si256(words.as_mut_ptr() as *mut __m256i, sums);
+        out[0] = (words[0] + words[1]) as u8;
+        out[1] = (words[2] + words[3]) as u8;
+    }
+    let start = features.len() - rest.len();
+    distances_scalar(search, rest, &mut distances[start..]);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f,avx512vpopcntdq")]
+unsafe fn distances_avx512(search: u128, features: &[u128], distances: &mut [u8]) {
+    let search_quad = _mm512_broadcast_i32x4(_mm_set_epi64x((search >> 64) as i64, search as i64));
+    let quads = features.chunks_exact(4);
+    let rest = quads.remainder();
+    let mut quad_distances = distances.chunks_exact_mut(4);
+    for (quad, out) in quads.zip(&mut quad_distances) {
+        let x = _mm512_xor_si512(_mm512_loadu_si512(quad.as_ptr() as *const _), search_quad);
+        let mut words = [0u64; 8];
+        _mm512_storeu_si512(words.as_mut_ptr() as *mut _, _mm512_popcnt_epi64(x));
+        for (distance, word) in out.iter_mut().zip(words.chunks_exact(2)) {
+            *distance = (word[0] + word[1]) as u8;
+        }
+    }
+    let start = features.len() - rest.len();
+    distances_scalar(search, rest, &mut distances[start..]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kernels() {
+        let features = (0..37u128)
+            .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15_F39C_C060_5CED_C835) ^ (i << 100))
+            .chain(vec![0, !0])
+            .collect::<Vec<u128>>();
+        let search = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210;
+        let expected = features
+            .iter()
+            .map(|&f| (f ^ search).count_ones() as u8)
+            .collect::<Vec<u8>>();
+        for (name, kernel) in kernels() {
+            let mut distances = vec![0; features.len()];
+            unsafe { kernel(search, &features, &mut distances) };
+            assert_eq!(distances, expected, "kernel({})", name);
+        }
+        let mut distances = vec![0; features.len()];
+        distances128(search, &features, &mut distances);
+        assert_eq!(distances, expected);
+    }
+}
@@ -1,5 +1,5 @@
+use crate::bits::*;
 use crate::search::*;
-use swar::*;
 
 /// Gets all the possible offsets in a feature that maintain a particular
 /// exact radius.
@@ -185,7 +185,7 @@ pub fn search_exact2(
 /// Iterator over the indices that fall within a radius of a number.
 ///
 /// - `bits` - The number of bits that make up the bit substring `sl`
-///     comes from.
+///   comes from.
 /// - `sl` - The weight of the left half of the search number.
 /// - `sw` - The weight of the whole search number.
 /// - `tw` - The weight of the whole target number.
@@ -232,16 +232,20 @@ pub fn search_exact(
         either::Left(min_inflection..=max_inflection)
             .filter(filter)
             .map(map)
-    } else if bottom_distance < radius {
-        // We intersect at precisely two locations.
-        let start = (-radius + c + 1) / 2;
-        let end = (radius + c) / 2;
+    } else if bottom_distance < radius && (radius - bottom_distance) % 2 == 0 {
+        // We intersect at precisely two locations. Every step away from the
+        // flat bottom changes the distance by two, so there are none if the
+        // radius differs from the bottom by an odd amount. Since the parity
+        // matches, both divisions are exact.
+        let start = (c - radius) / 2;
+        let end = (c + radius) / 2;
 
         either::Right(std::iter::once(start).chain(std::iter::once(end)))
             .filter(filter)
             .map(map)
     } else {
         // Create fake iterators to satisfy the type system.
+        #[allow(clippy::reversed_empty_ranges)]
         let flat = 0..=-1;
 
         // Also perform the same operations over here.
@@ -1,5 +1,5 @@
+use crate::bits::*;
 use itertools::Itertools;
-use swar::*;
 
 /// Gets all the possible offsets in a feature that maintain a particular
 /// radius at max.
@@ -195,7 +195,7 @@ pub fn search_radius2(
 /// Iterator over the indices that fall within a radius of a number.
 ///
 /// - `bits` - The number of bits that make up the bit substring `sl`
-///     comes from.
+///   comes from.
 /// - `sl` - The weight of the left half of the search number.
 /// - `sw` - The weight of the whole search number.
 /// - `tw` - The weight of the whole target number.
@@ -257,7 +257,9 @@ pub fn search_radius(
     } else {
         // Create fake iterators to satisfy the type system.
         let down = 0..0;
+        #[allow(clippy::reversed_empty_ranges)]
         let flat = 0..=-1;
+        #[allow(clippy::reversed_empty_ranges)]
         let up = 0..=-1;
 
         // Also perform the same operations over here.
@@ -0,0 +1,139 @@
+//! Statistics about the work done by searches and the shape of a `Hwt`.
+
+/// The work done by a nearest neighbor search, from `Hwt::nearest_with_stats`
+/// or `Knn::run_with_stats`.
+///
+/// These can be gathered from real queries to choose `max_error` and the
+/// thresholds in the `HwtConfig`. Every field is replaced by each search.
+///
+/// ```
+/// # use hwt::*;
+/// let hwt = Hwt::from_slice(&[0b1001u128, 0b1111]);
+/// let mut stats = QueryStats::default();
+/// let neighbors = hwt.knn(0b1011).run_with_stats(&mut stats);
+/// assert_eq!(neighbors[0].distance, 1);
+/// // The tree is so small that it is a single leaf vector.
+/// assert_eq!(stats.leaves_scanned, 2);
+/// assert_eq!(stats.nodes_popped, 0);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// The amount of nodes popped from the `NodeQueue`.
+    ///
+    /// A node searched with a precision search is put back into the queue
+    /// at the next distance, so it is popped once for every distance it is
+    /// searched at.
+    pub nodes_popped: usize,
+    /// The amount of times every child of a node was checked, for each
+    /// level of the tree.
+    ///
+    /// The levels are the same as the ones in
+    /// `HwtConfig::precision_search_thresholds`. The children of the root are
+    /// always checked, which isn't counted here.
+    pub brute_force_nodes: Vec<usize>,
+    /// The amount of times a node was searched with a precision search, for
+    /// each level of the tree.
+    pub precision_nodes: Vec<usize>,
+    /// The amount of child indices generated by the precision searches.
+    pub candidate_indices: usize,
+    /// The amount of child indices generated by the precision searches which
+    /// weren't children of the node.
+    pub hash_misses: usize,
+    /// The amount of leaves whose distance was computed.
+    pub leaves_scanned: usize,
+    /// The distance from the search feature's indices that the search
+    /// reached before it stopped.
+    pub search_distance: u32,
+}
+
+impl QueryStats {
+    /// Clears the statistics for a new search.
+    pub(crate) fn reset(&mut self) {
+        self.nodes_popped = 0;
+        self.brute_force_nodes.clear();
+        self.precision_nodes.clear();
+        self.candidate_indices = 0;
+        self.hash_misses = 0;
+        self.leaves_scanned = 0;
+        self.search_distance = 0;
+    }
+
+    /// Counts a node popped from the `NodeQueue` at `level`.
+    pub(crate) fn pop(&mut self, level: usize, precise: bool) {
+        self.nodes_popped += 1;
+        let counts = if precise {
+            &mut self.precision_nodes
+        } else {
+            &mut self.brute_force_nodes
+        };
+        increment(counts, level);
+    }
+}
+
+/// The shape and memory usage of a `Hwt`, from `Hwt::stats`.
+///
+/// Levels are the depth in the tree, so the root is at level `0`.
+///
+/// ```
+/// # use hwt::*;
+/// let hwt = Hwt::from_slice(&[0b1001u128, 0b1010, 0b1110]);
+/// let stats = hwt.stats();
+/// assert_eq!(stats.leaf_nodes, vec![1]);
+/// // The root is the only leaf vector and has 3 leaves, which takes 2 bits.
+/// assert_eq!(stats.leaf_sizes, vec![0, 0, 1]);
+/// assert_eq!(stats.root_weights[2], 2.0 / 3.0);
+/// assert_eq!(stats.root_weights[3], 1.0 / 3.0);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TreeStats {
+    /// The amount of entries in the tree.
+    pub len: usize,
+    /// The amount of levels which have nodes.
+    pub depth: usize,
+    /// The amount of leaf vectors at each level.
+    pub leaf_nodes: Vec<usize>,
+    /// The amount of `HashMap` nodes at each level.
+    pub map_nodes: Vec<usize>,
+    /// The amount of nodes which were freed by collapsing a `HashMap` and
+    /// are waiting to be reused.
+    pub free_nodes: usize,
+    /// The amount of leaf vectors by the amount of bits it takes to store
+    /// their length, so `leaf_sizes[0]` are empty, `leaf_sizes[1]` have one
+    /// leaf, `leaf_sizes[2]` have 2 to 3 leaves, `leaf_sizes[3]` have 4 to 7
+    /// leaves, and so on.
+    pub leaf_sizes: Vec<usize>,
+    /// The amount of `HashMap` nodes by the amount of bits it takes to store
+    /// their amount of children, the same way as `leaf_sizes`.
+    pub map_fan_out: Vec<usize>,
+    /// The heap memory in bytes used by the arrays of nodes themselves.
+    pub internals_bytes: usize,
+    /// The heap memory in bytes used by the `HashMap` nodes, which is
+    /// estimated from their capacity.
+    pub map_bytes: usize,
+    /// The heap memory in bytes used by the features and values of the leaf
+    /// vectors. Memory owned by the values themselves isn't included.
+    pub leaf_bytes: usize,
+    /// The fraction of the entries with each weight, which is the amount of
+    /// ones in the feature. These are the buckets of the root of the tree.
+    pub root_weights: Vec<f64>,
+}
+
+impl TreeStats {
+    /// The total heap memory in bytes used by the tree.
+    pub fn heap_bytes(&self) -> usize {
+        self.internals_bytes + self.map_bytes + self.leaf_bytes
+    }
+}
+
+/// Adds one to `counts[ix]`, extending `counts` with zeros if needed.
+pub(crate) fn increment(counts: &mut Vec<usize>, ix: usize) {
+    if counts.len() <= ix {
+        counts.resize(ix + 1, 0);
+    }
+    counts[ix] += 1;
+}
+
+/// The amount of bits it takes to store `n`.
+pub(crate) fn bits(n: usize) -> usize {
+    std::mem::size_of::<usize>() * 8 - n.leading_zeros() as usize
+}
@@ -0,0 +1,142 @@
+//! Choosing the thresholds of a `Hwt` by timing searches on this machine.
+//!
+//! The best thresholds depend on the distribution of the features, the size
+//! of the tree, and how fast each kind of search is on the current CPU.
+//! `calibrate` builds trial trees out of a sample of the features and times
+//! k-nearest neighbor searches for a sample of the queries with different
+//! thresholds.
+//!
+//! The timings are noisy, so calibrating twice may not give the same config.
+
+use crate::{HammingFeature, Hwt, HwtConfig};
+use log::debug;
+use std::time::{Duration, Instant};
+
+/// The leaf split thresholds which are tried.
+const SPLIT_CANDIDATES: [usize; 8] = [
+    1 << 6,
+    1 << 8,
+    1 << 10,
+    1 << 12,
+    1 << 14,
+    1 << 16,
+    1 << 18,
+    1 << 20,
+];
+
+/// The precision search thresholds which are tried at each level, from
+/// always using a precision search to never using one.
+const PRECISION_CANDIDATES: [usize; 7] = [0, 1 << 4, 1 << 6, 1 << 8, 1 << 10, 1 << 12, usize::MAX];
+
+/// Each trial is timed this many times and the fastest time is used.
+const ROUNDS: usize = 3;
+
+/// A threshold is only changed if that makes the searches faster by at least
+/// one part in this many, so that noise in the timings doesn't change the
+/// thresholds of levels that the sample never reaches.
+const MIN_IMPROVEMENT: u32 = 20;
+
+/// Chooses the thresholds which make finding the `k` nearest neighbors of
+/// `sample_queries` in a tree of `sample_features` the fastest on this machine.
+///
+/// The leaf split threshold is chosen first since it decides the shape of
+/// the tree. Then the precision search threshold of each level is chosen from
+/// the top of the tree down by timing `Hwt::knn` with each candidate. The
+/// samples should be representative of the real features and queries, and
+/// the tree should be about as large as the real one, since the best
+/// thresholds depend on the amount of features under each node.
+///
+/// Gives the default config if either sample is empty or `k` is `0`.
+///
+/// ```
+/// # use hwt::{tune, Hwt};
+/// let features = (0..1024u128).map(|i| i * 0x9E37_79B9_7F4A_7C15).collect::<Vec<_>>();
+/// let config = tune::calibrate(&features, &features[..16], 4);
+/// let mut hwt = Hwt::with_config(config).unwrap();
+/// hwt.extend(features.iter().map(|&f| (f, ())));
+/// assert_eq!(hwt.knn(features[0]).run()[0].distance, 0);
+/// ```
+pub fn calibrate<F: HammingFeature>(
+    sample_features: &[F],
+    sample_queries: &[F],
+    k: usize,
+) -> HwtConfig {
+    let mut config = HwtConfig::default();
+    if sample_features.is_empty() || sample_queries.is_empty() || k == 0 {
+        return config;
+    }
+
+    let mut hwt = build(sample_features, config);
+    let mut best = time(&hwt, sample_queries, k);
+    debug!("calibrate default time({:?})", best);
+    // A threshold of at least the amount of features is a single leaf vector.
+    for &split in SPLIT_CANDIDATES
+        .iter()
+        .filter(|&&split| split < sample_features.len())
+    {
+        let candidate = HwtConfig {
+            leaf_split_threshold: split,
+            leaf_collapse_threshold: split >> 2,
+            initial_leaf_capacity: std::cmp::min(config.initial_leaf_capacity, split),
+            ..config
+        };
+        let trial = build(sample_features, candidate);
+        let elapsed = time(&trial, sample_queries, k);
+        debug!("calibrate split({}) time({:?})", split, elapsed);
+        if improves(elapsed, best) {
+            config = candidate;
+            hwt = trial;
+            best = elapsed;
+        }
+    }
+
+    // The precision search thresholds don't change the shape of the tree,
+    // so the same tree is searched with each of them.
+    let levels = std::cmp::min(config.precision_search_thresholds.len(), F::levels());
+    for level in 0..levels {
+        for &threshold in PRECISION_CANDIDATES.iter() {
+            let mut candidate = config;
+            candidate.precision_search_thresholds[level] = threshold;
+            hwt.config = candidate;
+            let elapsed = time(&hwt, sample_queries, k);
+            debug!(
+                "calibrate level({}) precision({}) time({:?})",
+                level, threshold, elapsed
+            );
+            if improves(elapsed, best) {
+                config = candidate;
+                best = elapsed;
+            }
+        }
+        hwt.config = config;
+    }
+    config
+}
+
+/// Builds a trial tree out of `features` with `config`.
+fn build<F: HammingFeature>(features: &[F], config: HwtConfig) -> Hwt<(), F> {
+    let mut hwt = Hwt::with_config(config).expect("hwt: calibrate only tries valid configs");
+    hwt.extend(features.iter().map(|&feature| (feature, ())));
+    hwt
+}
+
+/// Times finding the `k` nearest neighbors of every query in `hwt`.
+fn time<F: HammingFeature>(hwt: &Hwt<(), F>, queries: &[F], k: usize) -> Duration {
+    let mut neighbors = Vec::with_capacity(k);
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            for &query in queries {
+                neighbors.clear();
+                hwt.knn(query).k(k).run_into(&mut neighbors);
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+/// Checks if `elapsed` is enough of an improvement over `best` to use it.
+fn improves(elapsed: Duration, best: Duration) -> bool {
+    elapsed + best / MIN_IMPROVEMENT < best
+}
@@ -0,0 +1,96 @@
+use hwt::matching::*;
+use hwt::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+/// Creates two sets of features where most of the second set are the first
+/// set with a few bits flipped, in a different order.
+fn image_pair() -> (Vec<u128>, Vec<u128>) {
+    let mut rng = SmallRng::from_seed([5; 16]);
+    let a = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(1 << 12)
+        .collect::<Vec<u128>>();
+    let mut b = vec![];
+    for &f in &a {
+        let mut f = f;
+        for _ in 0..rng.gen_range(0, 4) {
+            f ^= 1u128 << rng.gen_range(0u32, 128);
+        }
+        b.push(f);
+    }
+    // Add some features which shouldn't be matched.
+    b.extend(
+        rng.sample_iter::<u128, _>(&rand::distributions::Standard)
+            .take(1 << 10),
+    );
+    b.reverse();
+    (a, b)
+}
+
+fn nearest_distance(set: &[u128], feature: u128) -> u32 {
+    set.iter()
+        .map(|&f| (f ^ feature).count_ones())
+        .min()
+        .unwrap()
+}
+
+#[test]
+fn mutual_nearest_matches_linear() {
+    let (a, b) = image_pair();
+    let (hwt_a, hwt_b) = (Hwt::from_slice(&a), Hwt::from_slice(&b));
+    let matches = mutual_nearest(&hwt_a, &hwt_b);
+    for m in &matches {
+        assert_eq!((a[*m.a] ^ b[*m.b]).count_ones(), m.distance);
+        assert_eq!(nearest_distance(&b, a[*m.a]), m.distance);
+        assert_eq!(nearest_distance(&a, b[*m.b]), m.distance);
+    }
+    // Every feature in `a` is matched at most once.
+    let mut matched = matches.iter().map(|m| *m.a).collect::<Vec<usize>>();
+    matched.sort_unstable();
+    matched.dedup();
+    assert_eq!(matched.len(), matches.len());
+    // Nearly every feature should find its flipped copy.
+    assert!(matches.len() > a.len() * 9 / 10);
+    assert!(matches.iter().all(|m| *m.b == b.len() - 1 - *m.a));
+}
+
+#[test]
+fn mutual_nearest_zero_sized_values() {
+    // Both features in `a` are nearest to the one in `b`, which can only be
+    // nearest to one of them.
+    let mut a = Hwt::new();
+    a.insert(0b00u128, ());
+    a.insert(0b11, ());
+    let mut b = Hwt::new();
+    b.insert(0b01u128, ());
+    let matches = mutual_nearest(&a, &b);
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].distance, 1);
+}
+
+#[test]
+fn ratio_match_matches_linear() {
+    let (a, b) = image_pair();
+    let hwt = Hwt::from_slice(&b);
+    let matches = ratio_match(&a, &hwt, 0.8);
+    let mut matched = matches.iter();
+    let mut next = matched.next();
+    for (ix, &feature) in a.iter().enumerate() {
+        let mut distances = b
+            .iter()
+            .map(|&f| (f ^ feature).count_ones())
+            .collect::<Vec<u32>>();
+        distances.sort_unstable();
+        if (distances[0] as f32) < 0.8 * distances[1] as f32 {
+            let m = next.expect("a match was missed");
+            assert_eq!(m.a, ix);
+            assert_eq!(m.distance, distances[0]);
+            assert_eq!((b[*m.b] ^ feature).count_ones(), m.distance);
+            next = matched.next();
+        } else {
+            assert!(next.is_none_or(|m| m.a != ix));
+        }
+    }
+    assert!(next.is_none());
+}
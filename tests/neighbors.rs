@@ -17,12 +17,12 @@ fn test_neighbors() {
         0xAAAA_AAAA_AAAA_AAAA_AAAA_AAAA_AAAA_AAAA,
     ];
     let mut hwt = Hwt::new();
-    for &feature in &features {
-        hwt.insert(feature);
+    for (ix, &feature) in features.iter().enumerate() {
+        hwt.insert(feature, ix);
     }
 
-    for &feature in &features {
-        let mut neighbors = [0; 1];
+    for (ix, &feature) in features.iter().enumerate() {
+        let mut neighbors = [(0, &0); 1];
         let neighbors = hwt.nearest(
             feature,
             128,
@@ -31,29 +31,47 @@ fn test_neighbors() {
             &mut feature_heap,
             &mut neighbors,
         );
-        assert_eq!(neighbors[0], feature);
+        assert_eq!(neighbors[0], (feature, &ix));
     }
 
-    let mut neighbors = hwt.search_radius(1, 0b1000).collect::<Vec<u128>>();
+    let mut neighbors = hwt
+        .search_radius(1, 0b1000)
+        .map(|(f, _)| f)
+        .collect::<Vec<u128>>();
     neighbors.sort_unstable();
     assert_eq!(&neighbors, &[0b1000, 0b1001, 0b1010, 0b1100]);
 
-    let mut neighbors = hwt.search_radius(1, 0b1001).collect::<Vec<u128>>();
+    let mut neighbors = hwt
+        .search_radius(1, 0b1001)
+        .map(|(f, _)| f)
+        .collect::<Vec<u128>>();
     neighbors.sort_unstable();
     assert_eq!(&neighbors, &[0b1000, 0b1001]);
 
-    let mut neighbors = hwt.search_radius(1, 0b1010).collect::<Vec<u128>>();
+    let mut neighbors = hwt
+        .search_radius(1, 0b1010)
+        .map(|(f, _)| f)
+        .collect::<Vec<u128>>();
     neighbors.sort_unstable();
     assert_eq!(&neighbors, &[0b1000, 0b1010]);
 
-    let mut neighbors = hwt.search_radius(1, 0b1100).collect::<Vec<u128>>();
+    let mut neighbors = hwt
+        .search_radius(1, 0b1100)
+        .map(|(f, _)| f)
+        .collect::<Vec<u128>>();
     neighbors.sort_unstable();
     assert_eq!(&neighbors, &[0b1000, 0b1100]);
 
+    let distances = hwt
+        .nearest_iter(0b1000)
+        .map(|(distance, _, _)| distance)
+        .collect::<Vec<u32>>();
+    assert_eq!(distances, &[0, 1, 1, 1, 63]);
+
     let range = (0..).take(1 << 4);
     let mut hwt = Hwt::new();
     for i in range.clone() {
-        hwt.insert(i);
+        hwt.insert(i, ());
     }
     for feature in range.clone() {
         assert!(hwt.search_radius(2, feature).count() < 8128);
@@ -87,11 +105,11 @@ fn compare_to_linear() -> std::io::Result<()> {
         let space = &space[0..1 << i];
         let mut hwt = Hwt::new();
         for &f in space {
-            hwt.insert(f);
+            hwt.insert(f, ());
         }
 
         for &f0 in &search {
-            let mut neighbors_err0 = [0; 1];
+            let mut neighbors_err0 = [(0, &()); 1];
             let neighbors_err0 = hwt.nearest(
                 f0,
                 128,
@@ -100,7 +118,7 @@ fn compare_to_linear() -> std::io::Result<()> {
                 &mut feature_heap,
                 &mut neighbors_err0,
             );
-            let mut neighbors_err1 = [0; 1];
+            let mut neighbors_err1 = [(0, &()); 1];
             let neighbors_err1 = hwt.nearest(
                 f0,
                 128,
@@ -109,7 +127,7 @@ fn compare_to_linear() -> std::io::Result<()> {
                 &mut feature_heap,
                 &mut neighbors_err1,
             );
-            let mut neighbors_err2 = [0; 1];
+            let mut neighbors_err2 = [(0, &()); 1];
             let neighbors_err2 = hwt.nearest(
                 f0,
                 128,
@@ -118,7 +136,7 @@ fn compare_to_linear() -> std::io::Result<()> {
                 &mut feature_heap,
                 &mut neighbors_err2,
             );
-            let mut neighbors_err3 = [0; 1];
+            let mut neighbors_err3 = [(0, &()); 1];
             let neighbors_err3 = hwt.nearest(
                 f0,
                 128,
@@ -127,13 +145,47 @@ fn compare_to_linear() -> std::io::Result<()> {
                 &mut feature_heap,
                 &mut neighbors_err3,
             );
+            let mut neighbors_dist = [(0, 0, &()); 4];
+            let neighbors_dist = hwt.nearest_with_distance(
+                f0,
+                128,
+                0,
+                &mut node_queue,
+                &mut feature_heap,
+                &mut neighbors_dist,
+            );
+            let mut linear_distances = space
+                .iter()
+                .map(|&f1| (f0 ^ f1).count_ones())
+                .collect::<Vec<u32>>();
+            linear_distances.sort_unstable();
+            linear_distances.truncate(4);
+            assert_eq!(
+                neighbors_dist
+                    .iter()
+                    .map(|&(distance, _, _)| distance)
+                    .collect::<Vec<u32>>(),
+                linear_distances
+            );
+            for &(distance, f1, _) in neighbors_dist.iter() {
+                assert_eq!((f0 ^ f1).count_ones(), distance);
+            }
+            assert_eq!(
+                hwt.knn(f0)
+                    .k(4)
+                    .run()
+                    .iter()
+                    .map(|n| n.distance)
+                    .collect::<Vec<u32>>(),
+                linear_distances
+            );
             assert_eq!(
                 space
                     .iter()
                     .map(|&f1| (f0 ^ f1).count_ones())
                     .min()
                     .unwrap(),
-                (neighbors_err0[0] ^ f0).count_ones()
+                (neighbors_err0[0].0 ^ f0).count_ones()
             );
 
             assert!(
@@ -143,7 +195,7 @@ fn compare_to_linear() -> std::io::Result<()> {
                     .min()
                     .unwrap()
                     + 1
-                    >= (neighbors_err1[0] ^ f0).count_ones()
+                    >= (neighbors_err1[0].0 ^ f0).count_ones()
             );
 
             assert!(
@@ -153,7 +205,7 @@ fn compare_to_linear() -> std::io::Result<()> {
                     .min()
                     .unwrap()
                     + 2
-                    >= (neighbors_err2[0] ^ f0).count_ones()
+                    >= (neighbors_err2[0].0 ^ f0).count_ones()
             );
 
             assert!(
@@ -163,10 +215,615 @@ fn compare_to_linear() -> std::io::Result<()> {
                     .min()
                     .unwrap()
                     + 3
-                    >= (neighbors_err3[0] ^ f0).count_ones()
+                    >= (neighbors_err3[0].0 ^ f0).count_ones()
             );
         }
     }
 
     Ok(())
 }
+
+#[test]
+fn remove_and_collapse() {
+    let mut node_queue = NodeQueue::new();
+    let mut feature_heap = FeatureHeap::new();
+
+    let mut rng = SmallRng::from_seed([5; 16]);
+    // This is enough features to split the root into a `HashMap`.
+    let space = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(1 << 18)
+        .collect::<Vec<u128>>();
+    let search = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(10)
+        .collect::<Vec<u128>>();
+
+    let mut hwt = Hwt::new();
+    for (ix, &f) in space.iter().enumerate() {
+        hwt.insert(f, ix);
+    }
+
+    // Remove enough features that the tree must collapse back down.
+    let (removed, kept) = space.split_at(space.len() - (1 << 14));
+    for (ix, &f) in removed.iter().enumerate() {
+        assert_eq!(hwt.remove_by(f, |&v| v == ix), Some(ix));
+    }
+    assert_eq!(hwt.len(), kept.len());
+    assert!(removed
+        .iter()
+        .rev()
+        .take(1 << 10)
+        .all(|&f| !hwt.contains(f)));
+    assert!(kept.iter().all(|&f| hwt.contains(f)));
+
+    // Make sure the remaining features are still found after the collapse
+    // and that the freed nodes are reused properly.
+    for &f in removed.iter().take(1 << 10) {
+        hwt.insert(f, 0);
+        assert!(hwt.remove(f));
+    }
+    for &f0 in &search {
+        let mut neighbors = [(0, &0); 1];
+        let neighbors = hwt.nearest(
+            f0,
+            128,
+            0,
+            &mut node_queue,
+            &mut feature_heap,
+            &mut neighbors,
+        );
+        assert_eq!(
+            kept.iter().map(|&f1| (f0 ^ f1).count_ones()).min().unwrap(),
+            (neighbors[0].0 ^ f0).count_ones()
+        );
+    }
+}
+
+/// Compares the nearest neighbors of features of any width to a linear search.
+fn compare_width_to_linear<F: HammingFeature>(space: &[F], search: &[F]) {
+    let mut hwt: Hwt<usize, F> = Hwt::default();
+    for (ix, &f) in space.iter().enumerate() {
+        hwt.insert(f, ix);
+    }
+    assert!(space.iter().take(1 << 10).all(|&f| hwt.contains(f)));
+
+    let mut node_queue = NodeQueue::new();
+    let mut feature_heap = FeatureHeap::new();
+    let weights = (0..F::BITS)
+        .map(|bit| (bit * 7 % 5) as u8 + 1)
+        .collect::<Vec<u8>>();
+    for (&f0, &mask) in search.iter().zip(search.iter().rev()) {
+        let mut linear_distances = space
+            .iter()
+            .map(|&f1| f0.distance(f1))
+            .collect::<Vec<u32>>();
+        linear_distances.sort_unstable();
+        let nearest = hwt
+            .nearest_iter(f0)
+            .take(64)
+            .map(|(distance, feature, _)| {
+                assert_eq!(feature.distance(f0), distance);
+                distance
+            })
+            .collect::<Vec<u32>>();
+        assert_eq!(nearest, &linear_distances[..64]);
+        linear_distances.truncate(4);
+
+        // Only a third of the features are accepted.
+        let mut filtered_distances = (0..space.len())
+            .filter(|ix| ix % 3 == 0)
+            .map(|ix| f0.distance(space[ix]))
+            .collect::<Vec<u32>>();
+        filtered_distances.sort_unstable();
+        filtered_distances.truncate(4);
+        let neighbors = hwt.nearest_filtered(f0, 4, |_, &ix| ix % 3 == 0);
+        assert!(neighbors.iter().all(|n| n.value % 3 == 0));
+        assert_eq!(
+            neighbors.iter().map(|n| n.distance).collect::<Vec<u32>>(),
+            filtered_distances
+        );
+
+        // Only about half of the bits are compared.
+        let mut masked_distances = space
+            .iter()
+            .map(|&f1| f0.distance_masked(f1, mask))
+            .collect::<Vec<u32>>();
+        masked_distances.sort_unstable();
+        masked_distances.truncate(4);
+        let mut neighbors = [(f0, &0); 4];
+        let neighbors = hwt.nearest_masked(
+            f0,
+            mask,
+            F::BITS,
+            0,
+            &mut node_queue,
+            &mut feature_heap,
+            &mut neighbors,
+        );
+        assert_eq!(
+            neighbors
+                .iter()
+                .map(|&(f1, _)| f0.distance_masked(f1, mask))
+                .collect::<Vec<u32>>(),
+            masked_distances
+        );
+        let radius = masked_distances[3];
+        let mut found = hwt
+            .search_radius_masked(radius, f0, mask)
+            .map(|(_, &ix)| ix)
+            .collect::<Vec<usize>>();
+        found.sort_unstable();
+        let linear = (0..space.len())
+            .filter(|&ix| space[ix].distance_masked(f0, mask) <= radius)
+            .collect::<Vec<usize>>();
+        assert_eq!(found, linear);
+
+        let mut weighted_distances = space
+            .iter()
+            .map(|&f1| f0.distance_weighted(f1, &weights))
+            .collect::<Vec<u32>>();
+        weighted_distances.sort_unstable();
+        weighted_distances.truncate(4);
+        let mut neighbors = [(0, f0, &0); 4];
+        let neighbors = hwt.nearest_weighted(
+            f0,
+            &weights,
+            u32::MAX,
+            &mut node_queue,
+            &mut feature_heap,
+            &mut neighbors,
+        );
+        for &(distance, f1, &ix) in neighbors.iter() {
+            assert_eq!(f0.distance_weighted(f1, &weights), distance);
+            assert_eq!(space[ix], f1);
+        }
+        assert_eq!(
+            neighbors.iter().map(|n| n.0).collect::<Vec<u32>>(),
+            weighted_distances
+        );
+
+        let neighbors = hwt.knn(f0).k(4).run();
+        for neighbor in &neighbors {
+            assert_eq!(neighbor.feature.distance(f0), neighbor.distance);
+            assert_eq!(space[*neighbor.value], neighbor.feature);
+        }
+        assert_eq!(
+            neighbors.iter().map(|n| n.distance).collect::<Vec<u32>>(),
+            linear_distances
+        );
+
+        let radius = linear_distances[3];
+        let mut found = hwt
+            .search_radius(radius, f0)
+            .map(|(_, &ix)| ix)
+            .collect::<Vec<usize>>();
+        found.sort_unstable();
+        let linear = (0..space.len())
+            .filter(|&ix| space[ix].distance(f0) <= radius)
+            .collect::<Vec<usize>>();
+        assert_eq!(found, linear);
+
+        for (distance, feature, &ix) in hwt.search_radius_with_distance(radius, f0) {
+            assert_eq!(feature.distance(f0), distance);
+            assert_eq!(space[ix], feature);
+        }
+        let mut sorted = vec![];
+        hwt.search_radius_sorted(radius, f0, &mut feature_heap, &mut sorted);
+        assert!(sorted.windows(2).all(|w| w[0].0 <= w[1].0));
+        for &(distance, feature, _) in &sorted {
+            assert_eq!(feature.distance(f0), distance);
+        }
+        let mut found = sorted.iter().map(|&(_, _, &ix)| ix).collect::<Vec<usize>>();
+        found.sort_unstable();
+        assert_eq!(found, linear);
+
+        for &radius in &[radius, F::BITS / 2 - 8, F::BITS / 2, F::BITS] {
+            let linear = space
+                .iter()
+                .filter(|&&f1| f0.distance(f1) <= radius)
+                .count();
+            assert_eq!(hwt.count_within(radius, f0), linear);
+            assert_eq!(
+                hwt.count_within_capped(radius, f0, 3),
+                std::cmp::min(linear, 3)
+            );
+        }
+
+        for &(min, max) in &[
+            (radius, radius),
+            (radius - 2, radius + 2),
+            (F::BITS / 2 - 2, F::BITS / 2),
+        ] {
+            let mut found = hwt
+                .search_range(f0, min..=max)
+                .map(|(distance, feature, &ix)| {
+                    assert_eq!(feature.distance(f0), distance);
+                    ix
+                })
+                .collect::<Vec<usize>>();
+            found.sort_unstable();
+            let linear = (0..space.len())
+                .filter(|&ix| (min..=max).contains(&space[ix].distance(f0)))
+                .collect::<Vec<usize>>();
+            assert_eq!(found, linear);
+        }
+        let mut found = hwt
+            .search_exact_distance(f0, radius)
+            .map(|(_, &ix)| ix)
+            .collect::<Vec<usize>>();
+        found.sort_unstable();
+        let linear = (0..space.len())
+            .filter(|&ix| space[ix].distance(f0) == radius)
+            .collect::<Vec<usize>>();
+        assert_eq!(found, linear);
+    }
+}
+
+#[test]
+fn compare_widths_to_linear() {
+    let mut rng = SmallRng::from_seed([5; 16]);
+    // This is enough features to split the root into a `HashMap`.
+    let count = (1 << 17) + (1 << 12);
+
+    let space = (0..count).map(|_| rng.gen()).collect::<Vec<u64>>();
+    let search = (0..10).map(|_| rng.gen()).collect::<Vec<u64>>();
+    compare_width_to_linear(&space, &search);
+
+    let space = (0..count).map(|_| rng.gen()).collect::<Vec<[u64; 4]>>();
+    let search = (0..10).map(|_| rng.gen()).collect::<Vec<[u64; 4]>>();
+    compare_width_to_linear(&space, &search);
+
+    let space = (0..count).map(|_| rng.gen()).collect::<Vec<[u128; 4]>>();
+    let search = (0..10).map(|_| rng.gen()).collect::<Vec<[u128; 4]>>();
+    compare_width_to_linear(&space, &search);
+}
+
+#[test]
+fn bulk_construction() {
+    let mut rng = SmallRng::from_seed([5; 16]);
+    // This is enough features to split the root and some of its children.
+    let space = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(1 << 19)
+        .collect::<Vec<u128>>();
+    let search = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(10)
+        .collect::<Vec<u128>>();
+
+    let mut inserted = Hwt::new();
+    for (ix, &f) in space.iter().enumerate() {
+        inserted.insert(f, ix);
+    }
+    let mut built = Hwt::from_slice(&space[..space.len() - (1 << 10)]);
+    // Extending a tree that isn't empty inserts the features.
+    built.extend(
+        space
+            .iter()
+            .cloned()
+            .enumerate()
+            .skip(space.len() - (1 << 10))
+            .map(|(ix, f)| (f, ix)),
+    );
+    assert_eq!(built.len(), inserted.len());
+    assert!(space.iter().take(1 << 10).all(|&f| built.contains(f)));
+
+    let mut feature_heap = FeatureHeap::new();
+    for &f0 in &search {
+        let mut a = built.search_radius(40, f0).collect::<Vec<_>>();
+        let mut b = inserted.search_radius(40, f0).collect::<Vec<_>>();
+        a.sort_unstable();
+        b.sort_unstable();
+        assert_eq!(a, b);
+        let mut sorted = vec![];
+        built.search_radius_sorted(40, f0, &mut feature_heap, &mut sorted);
+        assert!(sorted.windows(2).all(|w| w[0].0 <= w[1].0));
+        let mut c = sorted
+            .into_iter()
+            .map(|(_, f, ix)| (f, ix))
+            .collect::<Vec<_>>();
+        c.sort_unstable();
+        assert_eq!(a, c);
+        assert_eq!(
+            built
+                .knn(f0)
+                .k(4)
+                .run()
+                .iter()
+                .map(|n| n.distance)
+                .collect::<Vec<_>>(),
+            inserted
+                .knn(f0)
+                .k(4)
+                .run()
+                .iter()
+                .map(|n| n.distance)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    // Removing everything must collapse the tree just like an inserted one.
+    for (ix, &f) in space.iter().enumerate() {
+        assert_eq!(built.remove_by(f, |&v| v == ix), Some(ix));
+    }
+    assert!(built.is_empty());
+}
+
+#[test]
+fn bulk_construction_matches_insert_shape() {
+    let mut rng = SmallRng::from_seed([12; 16]);
+    // Many copies of one feature fill a leaf vector at the last level.
+    let space = (0..1 << 10)
+        .map(|_| rng.gen())
+        .chain(std::iter::repeat_n(0b1011, 1 << 8))
+        .collect::<Vec<u128>>();
+    let config = HwtConfig {
+        leaf_split_threshold: 1 << 4,
+        leaf_collapse_threshold: 1 << 2,
+        ..HwtConfig::default()
+    };
+    let mut inserted = Hwt::with_config(config).unwrap();
+    for (ix, &f) in space.iter().enumerate() {
+        inserted.insert(f, ix);
+    }
+    let mut built = Hwt::with_config(config).unwrap();
+    built.extend(space.iter().cloned().zip(0..));
+    let (a, b) = (inserted.stats(), built.stats());
+    assert_eq!(
+        (a.depth, &a.leaf_nodes, &a.map_nodes),
+        (b.depth, &b.leaf_nodes, &b.map_nodes)
+    );
+    for hwt in &[&inserted, &built] {
+        assert_eq!(hwt.knn(0b1011).k(1 << 8).run().last().unwrap().distance, 0);
+    }
+
+    // Extending an emptied tree rebuilds it without the old nodes.
+    for (ix, &f) in space.iter().enumerate() {
+        assert_eq!(built.remove_by(f, |&v| v == ix), Some(ix));
+    }
+    built.extend(space.iter().cloned().zip(0..));
+    let c = built.stats();
+    assert_eq!(c.free_nodes, 0);
+    assert_eq!(
+        (c.len, c.depth, &c.leaf_nodes, &c.map_nodes, &c.leaf_sizes),
+        (b.len, b.depth, &b.leaf_nodes, &b.map_nodes, &b.leaf_sizes)
+    );
+}
+
+#[test]
+fn knn_finds_whole_tree() {
+    let mut rng = SmallRng::from_seed([10; 16]);
+    let config = HwtConfig {
+        leaf_split_threshold: 1 << 4,
+        leaf_collapse_threshold: 1 << 2,
+        initial_leaf_capacity: 1 << 2,
+        ..HwtConfig::default()
+    };
+    let space = (0..1 << 10).map(|_| rng.gen()).collect::<Vec<u64>>();
+    let mut hwt = Hwt::<usize, u64>::with_config(config).unwrap();
+    hwt.extend(space.iter().cloned().zip(0..));
+    for &f0 in space.iter().step_by(1 << 6) {
+        // Asking for more than the tree has and any distance gives every leaf.
+        let neighbors = hwt
+            .knn(f0)
+            .k(space.len() + 1)
+            .max_distance(u32::MAX)
+            .max_error(u32::MAX)
+            .run();
+        let mut expected = space
+            .iter()
+            .map(|&f1| (f0 ^ f1).count_ones())
+            .collect::<Vec<u32>>();
+        expected.sort_unstable();
+        assert_eq!(
+            neighbors.iter().map(|n| n.distance).collect::<Vec<u32>>(),
+            expected
+        );
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn batch_matches_single() {
+    let mut rng = SmallRng::from_seed([5; 16]);
+    let space = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(1 << 18)
+        .collect::<Vec<u128>>();
+    let search = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(64)
+        .collect::<Vec<u128>>();
+    let hwt = Hwt::from_slice(&space);
+
+    let batch = hwt.nearest_batch(&search, 4, 0);
+    let radius = hwt.par_search_radius(40, &search);
+    assert_eq!(batch.len(), search.len());
+    assert_eq!(radius.len(), search.len());
+    for ((&f0, neighbors), mut found) in search.iter().zip(batch).zip(radius) {
+        assert_eq!(neighbors, hwt.knn(f0).k(4).run());
+        let mut expected = hwt.search_radius(40, f0).collect::<Vec<_>>();
+        found.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn joins_match_radius_search() {
+    let mut rng = SmallRng::from_seed([5; 16]);
+    // This is enough features to split the root into a `HashMap`.
+    // The features have a wide range of weights so that the leaf vectors
+    // under the root are small enough to check against radius searches.
+    let mut space = (0..(1 << 17) + (1 << 12))
+        .map(|_| u128::from(rng.gen::<u64>()) | u128::from(!0u64 >> rng.gen_range(0, 64)) << 64)
+        .collect::<Vec<u128>>();
+    // Add near-duplicates of some of the features.
+    let duplicates = space
+        .iter()
+        .step_by(16)
+        .map(|&f| f ^ 1 << (f % 128) ^ 1 << (f >> 121))
+        .collect::<Vec<u128>>();
+    space.extend(duplicates.iter().cloned());
+    let hwt = Hwt::from_slice(&space);
+    let other = Hwt::from_slice(&duplicates);
+    let radius = 4;
+
+    let mut pairs = hwt
+        .self_join(radius)
+        .iter()
+        .map(|m| {
+            assert_eq!(space[*m.a].distance(space[*m.b]), m.distance);
+            (std::cmp::min(*m.a, *m.b), std::cmp::max(*m.a, *m.b))
+        })
+        .collect::<Vec<(usize, usize)>>();
+    pairs.sort_unstable();
+    let mut expected = vec![];
+    for (a, &f) in space.iter().enumerate() {
+        expected.extend(
+            hwt.search_radius(radius, f)
+                .map(|(_, &b)| (a, b))
+                .filter(|&(a, b)| a < b),
+        );
+    }
+    expected.sort_unstable();
+    assert!(expected.len() >= duplicates.len());
+    assert_eq!(pairs, expected);
+
+    let mut pairs = hwt
+        .join(&other, radius)
+        .iter()
+        .map(|m| {
+            assert_eq!(space[*m.a].distance(duplicates[*m.b]), m.distance);
+            (*m.a, *m.b)
+        })
+        .collect::<Vec<(usize, usize)>>();
+    pairs.sort_unstable();
+    let mut expected = vec![];
+    for (b, &f) in duplicates.iter().enumerate() {
+        expected.extend(hwt.search_radius(radius, f).map(|(_, &a)| (a, b)));
+    }
+    expected.sort_unstable();
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn calibrated_config_matches_linear() {
+    let mut rng = SmallRng::from_seed([9; 16]);
+    // Features which are far from uniform.
+    let space = (0..1 << 14)
+        .map(|_| u128::from(rng.gen::<u64>()) | u128::from(!0u64 >> rng.gen_range(0, 64)) << 64)
+        .collect::<Vec<u128>>();
+    let search = (0..32).map(|_| rng.gen()).collect::<Vec<u128>>();
+    let config = tune::calibrate(&space, &search, 8);
+    assert_eq!(config.validate(), Ok(()));
+
+    let mut hwt = Hwt::with_config(config).unwrap();
+    hwt.extend(space.iter().map(|&f| (f, ())));
+    for &f in &search {
+        let mut expected = space
+            .iter()
+            .map(|&f1| (f ^ f1).count_ones())
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        expected.truncate(8);
+        let found = hwt
+            .knn(f)
+            .k(8)
+            .run()
+            .iter()
+            .map(|n| n.distance)
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected);
+    }
+}
+
+#[test]
+fn query_stats_count_work() {
+    let mut rng = SmallRng::from_seed([10; 16]);
+    let space = (0..1 << 14).map(|_| rng.gen()).collect::<Vec<u128>>();
+    let search = (0..32).map(|_| rng.gen()).collect::<Vec<u128>>();
+    for &precision in &[0, usize::MAX] {
+        let config = HwtConfig {
+            leaf_split_threshold: 1 << 6,
+            leaf_collapse_threshold: 1 << 4,
+            precision_search_thresholds: [precision; 7],
+            ..HwtConfig::default()
+        };
+        let mut hwt = Hwt::with_config(config).unwrap();
+        hwt.extend(space.iter().map(|&f| (f, ())));
+        let mut stats = QueryStats::default();
+        for &f in &search {
+            let neighbors = hwt.knn(f).k(8).run_with_stats(&mut stats);
+            assert_eq!(neighbors, hwt.knn(f).k(8).run());
+            let mut expected = space
+                .iter()
+                .map(|&f1| (f ^ f1).count_ones())
+                .collect::<Vec<_>>();
+            expected.sort_unstable();
+            expected.truncate(8);
+            assert_eq!(
+                neighbors.iter().map(|n| n.distance).collect::<Vec<_>>(),
+                expected
+            );
+            let brute_force = stats.brute_force_nodes.iter().sum::<usize>();
+            let precise = stats.precision_nodes.iter().sum::<usize>();
+            assert_eq!(stats.nodes_popped, brute_force + precise);
+            assert!(stats.nodes_popped > 0);
+            assert!(stats.hash_misses <= stats.candidate_indices);
+            assert!(stats.leaves_scanned >= 8 && stats.leaves_scanned <= space.len());
+            assert!(stats.search_distance <= 128);
+            if precision == 0 {
+                assert!(precise > 0 && stats.candidate_indices > 0);
+            } else {
+                assert_eq!((precise, stats.candidate_indices), (0, 0));
+            }
+        }
+    }
+}
+
+#[test]
+fn tree_stats_describe_shape() {
+    let mut rng = SmallRng::from_seed([11; 16]);
+    let space = (0..1 << 14).map(|_| rng.gen()).collect::<Vec<u128>>();
+    let config = HwtConfig {
+        leaf_split_threshold: 1 << 6,
+        leaf_collapse_threshold: 1 << 4,
+        ..HwtConfig::default()
+    };
+    let mut hwt = Hwt::with_config(config).unwrap();
+    hwt.extend(space.iter().map(|&f| (f, 0u64)));
+    // Collapse some nodes so that there are freed nodes.
+    for &f in &space[..1 << 13] {
+        assert!(hwt.remove(f));
+    }
+
+    let stats = hwt.stats();
+    assert_eq!(stats.len, 1 << 13);
+    assert_eq!(stats.map_nodes[0], 1);
+    assert_eq!(stats.leaf_nodes.len(), stats.depth);
+    assert_eq!(stats.map_nodes.len(), stats.depth);
+    assert!(stats.depth > 2);
+    assert!(stats.free_nodes > 0);
+    assert_eq!(
+        stats.leaf_sizes.iter().sum::<usize>(),
+        stats.leaf_nodes.iter().sum::<usize>()
+    );
+    assert_eq!(
+        stats.map_fan_out.iter().sum::<usize>(),
+        stats.map_nodes.iter().sum::<usize>()
+    );
+    // No leaf vector can be larger than the split threshold.
+    assert!(stats.leaf_sizes.len() <= 8);
+    assert!(stats.leaf_bytes >= stats.len * (16 + 8));
+    assert!(stats.map_bytes > 0);
+
+    let mut weights = vec![0.0; 129];
+    for (f, _) in hwt.iter() {
+        weights[f.count_ones() as usize] += 1.0 / stats.len as f64;
+    }
+    for (&a, &b) in stats.root_weights.iter().zip(&weights) {
+        assert!((a - b).abs() < 1e-9);
+    }
+}
@@ -1,7 +1,7 @@
-use hwt::{indices::*, search::*};
+use hwt::{indices::*, search::*, HammingFeature};
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
-use swar::*;
+use hwt::bits::*;
 
 #[test]
 fn test_search2() {
@@ -49,7 +49,9 @@ fn test_search2() {
             }
 
             assert!(search_radius2(64, sp, sc, tp, distance).any(|(exact_tc, _)| exact_tc == tc));
-            assert!(search_radius2(64, sp, sc, tp, distance + 1).any(|(exact_tc, _)| exact_tc == tc));
+            assert!(
+                search_radius2(64, sp, sc, tp, distance + 1).any(|(exact_tc, _)| exact_tc == tc)
+            );
             for (Bits64(exact_tc), sod) in search_radius2(64, sp, sc, tp, distance) {
                 assert_eq!(
                     (exact_tc ^ sc.0).count_ones(),
@@ -63,3 +65,67 @@ fn test_search2() {
         }
     }
 }
+
+#[test]
+fn test_search_exact_weights() {
+    // Check every combination of weights against the distance it yields.
+    for &bits in &[1u32, 2, 4, 8, 16, 32] {
+        for sw in 0..=2 * bits {
+            for sl in sw.saturating_sub(bits)..=std::cmp::min(sw, bits) {
+                for tw in 0..=2 * bits {
+                    for radius in 0..=2 * bits + 1 {
+                        for [tl, tr] in search_exact(bits, sl, sw, tw, radius) {
+                            let distance = (sl as i32 - tl as i32).abs()
+                                + ((sw - sl) as i32 - tr as i32).abs();
+                            assert_eq!(
+                                distance as u32, radius,
+                                "bits({}) sl({}) sw({}) tw({}) tl({})",
+                                bits, sl, sw, tw, tl
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_feature_search_radius() {
+    let mut rng = SmallRng::from_seed([5; 16]);
+    let search = rng
+        .sample_iter(&rand::distributions::Standard)
+        .take(10)
+        .collect::<Vec<u128>>();
+
+    for &s in &search {
+        let sindices = indices128(s);
+        // Use targets close to the search feature so that the amount of
+        // children within the radius stays reasonable at every level.
+        for _ in 0..20 {
+            let flips = rng.gen_range(1, 4);
+            let t = (0..flips).fold(s, |t, _| t ^ (1 << rng.gen_range(0, 128)));
+            let tindices = indices128(t);
+            for level in 0..7 {
+                let sod = (sindices[level + 1] ^ tindices[level + 1]).count_ones();
+                for radius in sod.saturating_sub(1)..=sod + 1 {
+                    let found = <u128 as HammingFeature>::search_radius(
+                        level,
+                        sindices[level],
+                        sindices[level + 1],
+                        tindices[level + 1],
+                        radius,
+                    )
+                    .unwrap()
+                    .collect::<Vec<u128>>();
+                    // The target must be found exactly when it is in the radius.
+                    assert_eq!(found.contains(&tindices[level + 1]), sod <= radius);
+                    for tc in found {
+                        assert!((tc ^ sindices[level + 1]).count_ones() <= radius);
+                        assert_eq!(tc.count_ones(), tindices[level].count_ones());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use hwt::*;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+/// Builds a tree big enough that the root is split into a `HashMap`.
+fn build() -> (Vec<u128>, Hwt<u32>) {
+    let mut rng = SmallRng::from_seed([5; 16]);
+    let space = (0..(1 << 17) + (1 << 12))
+        .map(|_| rng.gen())
+        .collect::<Vec<u128>>();
+    let mut hwt = Hwt::new();
+    for (ix, &f) in space.iter().enumerate() {
+        hwt.insert(f, ix as u32);
+    }
+    // Remove some features so that there are freed nodes.
+    for &f in &space[..1 << 14] {
+        assert!(hwt.remove(f));
+    }
+    (space, hwt)
+}
+
+fn assert_same(space: &[u128], a: &Hwt<u32>, b: &Hwt<u32>) {
+    assert_eq!(a.len(), b.len());
+    for &f in space.iter().step_by(1 << 8) {
+        let mut a_found = a.search_radius(0, f).collect::<Vec<_>>();
+        let mut b_found = b.search_radius(0, f).collect::<Vec<_>>();
+        a_found.sort_unstable();
+        b_found.sort_unstable();
+        assert_eq!(a_found, b_found);
+        assert_eq!(
+            a.knn(f)
+                .k(8)
+                .run()
+                .iter()
+                .map(|n| n.distance)
+                .collect::<Vec<_>>(),
+            b.knn(f)
+                .k(8)
+                .run()
+                .iter()
+                .map(|n| n.distance)
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn binary_round_trip() {
+    let (space, hwt) = build();
+    let mut bytes = vec![];
+    hwt.write_to(&mut bytes).unwrap();
+    let mut loaded = Hwt::read_from(&mut bytes.as_slice()).unwrap();
+    assert_same(&space, &hwt, &loaded);
+
+    // The freed nodes must still be usable after loading.
+    for (ix, &f) in space[..1 << 14].iter().enumerate() {
+        loaded.insert(f, ix as u32);
+    }
+    assert_eq!(loaded.len(), space.len());
+}
+
+#[test]
+fn binary_rejects_invalid() {
+    let mut hwt = Hwt::new();
+    for f in 0..64 {
+        hwt.insert(f, ());
+    }
+    let mut bytes = vec![];
+    hwt.write_to(&mut bytes).unwrap();
+
+    // A flipped bit is caught by the checksum.
+    let mut corrupt = bytes.clone();
+    corrupt[40] ^= 1;
+    let err = Hwt::<()>::read_from(&mut corrupt.as_slice()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // The features must have the same width.
+    let err = Hwt::<(), [u64; 4]>::read_from(&mut bytes.as_slice())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // Truncated input is an error rather than a partial tree.
+    assert!(Hwt::<()>::read_from(&mut &bytes[..bytes.len() - 1]).is_err());
+    assert!(Hwt::<()>::read_from(&mut &b"not a tree"[..]).is_err());
+}
+
+/// Stores a `Hwt<()>` whose nodes are given directly, where `Some(child)` is
+/// a `HashMap` with a single child and `None` is an empty leaf vector.
+fn crafted(nodes: &[Option<u32>]) -> Vec<u8> {
+    // An empty tree ends with the count, one empty leaf vector, no free
+    // nodes, and the checksum, so everything before that is the header.
+    let mut empty = vec![];
+    Hwt::<()>::new().write_to(&mut empty).unwrap();
+    let mut bytes = empty[..empty.len() - 41].to_vec();
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&(nodes.len() as u64).to_le_bytes());
+    for node in nodes {
+        match node {
+            Some(child) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&0u64.to_le_bytes());
+                bytes.extend_from_slice(&1u64.to_le_bytes());
+                bytes.extend_from_slice(&0u128.to_le_bytes());
+                bytes.extend_from_slice(&child.to_le_bytes());
+            }
+            None => {
+                bytes.push(0);
+                bytes.extend_from_slice(&0u64.to_le_bytes());
+            }
+        }
+    }
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    // The 64-bit FNV-1a checksum.
+    let checksum = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+#[test]
+fn binary_rejects_malformed_trees() {
+    assert!(Hwt::<()>::read_from(&mut crafted(&[Some(1), None]).as_slice()).is_ok());
+    // A node which is its own child.
+    assert!(Hwt::<()>::read_from(&mut crafted(&[Some(0)]).as_slice()).is_err());
+    // A node which is the child of its own child.
+    assert!(Hwt::<()>::read_from(&mut crafted(&[Some(1), Some(0)]).as_slice()).is_err());
+    // A node which isn't in the tree.
+    assert!(Hwt::<()>::read_from(&mut crafted(&[None, None]).as_slice()).is_err());
+    // A `HashMap` on every level, including the last one.
+    let mut deep = (1..=8).map(Some).collect::<Vec<_>>();
+    deep.push(None);
+    assert!(Hwt::<()>::read_from(&mut crafted(&deep).as_slice()).is_err());
+    deep.truncate(7);
+    deep.push(None);
+    assert!(Hwt::<()>::read_from(&mut crafted(&deep).as_slice()).is_ok());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_rejects_malformed_trees() {
+    // The fields of `Hwt` in order, with the nodes as enum variant indices.
+    let no_nodes = (
+        Vec::<(u32, Vec<(u128, u32)>)>::new(),
+        Vec::<usize>::new(),
+        Vec::<u32>::new(),
+        0usize,
+        HwtConfig::default(),
+    );
+    let bytes = bincode::serialize(&no_nodes).unwrap();
+    assert!(bincode::deserialize::<Hwt<()>>(&bytes).is_err());
+    let cycle = (
+        vec![(1u32, vec![(0u128, 0u32)])],
+        vec![0usize],
+        Vec::<u32>::new(),
+        0usize,
+        HwtConfig::default(),
+    );
+    let bytes = bincode::serialize(&cycle).unwrap();
+    assert!(bincode::deserialize::<Hwt<()>>(&bytes).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let (space, hwt) = build();
+    let bytes = bincode::serialize(&hwt).unwrap();
+    let loaded: Hwt<u32> = bincode::deserialize(&bytes).unwrap();
+    assert_same(&space, &hwt, &loaded);
+}
+
+#[test]
+fn frozen_matches_hwt() {
+    let (space, hwt) = build();
+    let (frozen, values) = hwt.freeze();
+    assert_eq!(frozen.len(), hwt.len());
+    assert_eq!(values.len(), hwt.len());
+
+    // Use the bytes the way a memory-mapped file would be used.
+    let frozen: FrozenHwt<&[u8]> = FrozenHwt::new(frozen.as_bytes()).unwrap();
+    let mut node_queue = NodeQueue::new();
+    let mut feature_heap = FeatureHeap::new();
+    for &f in space.iter().step_by(1 << 8) {
+        let mut neighbors = [(0, 0); 8];
+        let neighbors = frozen.nearest(
+            f,
+            128,
+            0,
+            &mut node_queue,
+            &mut feature_heap,
+            &mut neighbors,
+        );
+        assert_eq!(
+            neighbors
+                .iter()
+                .map(|&(f1, _)| (f ^ f1).count_ones())
+                .collect::<Vec<_>>(),
+            hwt.knn(f)
+                .k(8)
+                .run()
+                .iter()
+                .map(|n| n.distance)
+                .collect::<Vec<_>>()
+        );
+        for &(f1, leaf) in neighbors.iter() {
+            assert_eq!(space[*values[leaf] as usize], f1);
+        }
+
+        let mut frozen_found = frozen
+            .search_radius(8, f)
+            .map(|(f1, leaf)| (f1, values[leaf]))
+            .collect::<Vec<_>>();
+        let mut found = hwt.search_radius(8, f).collect::<Vec<_>>();
+        frozen_found.sort_unstable();
+        found.sort_unstable();
+        assert_eq!(frozen_found, found);
+    }
+
+    // Asking for more than the tree has with any distance gives every leaf.
+    let mut neighbors = vec![(0, 0); hwt.len() + 1];
+    let neighbors = frozen.nearest(
+        space[0],
+        u32::MAX,
+        u32::MAX,
+        &mut node_queue,
+        &mut feature_heap,
+        &mut neighbors,
+    );
+    assert_eq!(neighbors.len(), hwt.len());
+}
+
+#[test]
+fn frozen_rejects_invalid() {
+    let mut hwt = Hwt::new();
+    for f in 0..64 {
+        hwt.insert(f, ());
+    }
+    let (frozen, _) = hwt.freeze();
+    let bytes = frozen.into_inner();
+    assert!(FrozenHwt::<&[u8]>::new(&bytes[..bytes.len() - 1]).is_err());
+    assert!(FrozenHwt::<&[u8], u64>::new(&bytes[..]).is_err());
+    assert!(FrozenHwt::<&[u8]>::new(&b"not a tree"[..]).is_err());
+
+    assert!(FrozenHwt::<Vec<u8>>::new(frozen_crafted(&[&[1], &[]])).is_ok());
+    // A node which is its own child.
+    assert!(FrozenHwt::<Vec<u8>>::new(frozen_crafted(&[&[0]])).is_err());
+    // A node which is the child of its own child.
+    assert!(FrozenHwt::<Vec<u8>>::new(frozen_crafted(&[&[1], &[0]])).is_err());
+    // A node which is the child of two entries.
+    assert!(FrozenHwt::<Vec<u8>>::new(frozen_crafted(&[&[1, 2], &[], &[]])).is_ok());
+    assert!(FrozenHwt::<Vec<u8>>::new(frozen_crafted(&[&[1, 1], &[]])).is_err());
+    // A `HashMap` on every level, including the last one.
+    let mut deep = (1..=8).map(|child| vec![child]).collect::<Vec<_>>();
+    deep.push(vec![]);
+    let mut deep_nodes = deep.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    assert!(FrozenHwt::<Vec<u8>>::new(frozen_crafted(&deep_nodes)).is_err());
+    deep_nodes.truncate(7);
+    deep_nodes.push(&[]);
+    assert!(FrozenHwt::<Vec<u8>>::new(frozen_crafted(&deep_nodes)).is_ok());
+    // A node which is neither a leaf vector nor a `HashMap`.
+    let mut unknown = frozen_crafted(&[&[1], &[]]);
+    unknown[96 + 24] = 2;
+    assert!(FrozenHwt::<Vec<u8>>::new(unknown).is_err());
+}
+
+/// Lays out a `FrozenHwt<Vec<u8>>` whose nodes are given directly, where
+/// each node is a `HashMap` with the given children or an empty leaf vector
+/// if it has none.
+fn frozen_crafted(nodes: &[&[u32]]) -> Vec<u8> {
+    let entries = nodes.iter().map(|children| children.len()).sum::<usize>();
+    let mut bytes = b"HWTF".to_vec();
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&128u32.to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    for count in &[0, nodes.len(), entries] {
+        bytes.extend_from_slice(&(*count as u64).to_le_bytes());
+    }
+    for &threshold in &HwtConfig::default().precision_search_thresholds {
+        bytes.extend_from_slice(&(threshold as u64).to_le_bytes());
+    }
+    let mut start = 0;
+    for children in nodes {
+        // Leaf vectors have no leaves, so they start at `0`.
+        let (kind, node_start) = if children.is_empty() {
+            (0u32, 0)
+        } else {
+            (1, start)
+        };
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&(node_start as u64).to_le_bytes());
+        bytes.extend_from_slice(&(children.len() as u64).to_le_bytes());
+        start += children.len();
+    }
+    // The entries of each `HashMap` have the indices `0`, `1`, and so on.
+    for children in nodes {
+        for tc in 0..children.len() as u128 {
+            bytes.extend_from_slice(&tc.to_le_bytes());
+        }
+    }
+    for &child in nodes.iter().copied().flatten() {
+        bytes.extend_from_slice(&child.to_le_bytes());
+    }
+    bytes
+}
+
+#[test]
+fn config_round_trip() {
+    let config = HwtConfig {
+        leaf_split_threshold: 64,
+        leaf_collapse_threshold: 16,
+        precision_search_thresholds: [1, 2, 4, 8, 16, 32, 64],
+        initial_leaf_capacity: 4,
+    };
+    let invalid = HwtConfig {
+        leaf_collapse_threshold: 65,
+        ..config
+    };
+    assert_eq!(
+        Hwt::<()>::with_config(invalid).err(),
+        Some(ConfigError::CollapseAboveSplit)
+    );
+    // Empty `HashMap`s are never precision searched, so zero is fine for both.
+    let never_collapse = HwtConfig {
+        leaf_collapse_threshold: 0,
+        precision_search_thresholds: [0, 2, 4, 8, 16, 32, 64],
+        ..config
+    };
+    assert!(Hwt::<()>::with_config(never_collapse).is_ok());
+
+    let mut rng = SmallRng::from_seed([6; 16]);
+    let space = (0..1 << 12).map(|_| rng.gen()).collect::<Vec<u128>>();
+    let mut hwt = Hwt::with_config(config).unwrap();
+    hwt.extend(space.iter().cloned().zip(0..));
+    for &f in &space[..1 << 8] {
+        hwt.insert(f, 0);
+    }
+
+    let mut bytes = vec![];
+    hwt.write_to(&mut bytes).unwrap();
+    let loaded = Hwt::read_from(&mut bytes.as_slice()).unwrap();
+    assert_eq!(loaded.config(), &config);
+    assert_same(&space, &hwt, &loaded);
+    for &f in space.iter().step_by(1 << 6) {
+        let mut found = loaded.search_radius(40, f).collect::<Vec<_>>();
+        let mut expected = space
+            .iter()
+            .zip(0..)
+            .filter(|&(&f1, _)| (f ^ f1).count_ones() <= 40)
+            .map(|(&f1, ix)| (f1, ix))
+            .chain(
+                space[..1 << 8]
+                    .iter()
+                    .filter(|&&f1| (f ^ f1).count_ones() <= 40)
+                    .map(|&f1| (f1, 0)),
+            )
+            .collect::<Vec<_>>();
+        found.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(
+            found
+                .into_iter()
+                .map(|(f1, &v)| (f1, v))
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    {
+        let bytes = bincode::serialize(&hwt).unwrap();
+        let loaded: Hwt<u32> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(loaded.config(), &config);
+    }
+}